@@ -0,0 +1,72 @@
+use std::borrow::Cow;
+
+use crate::blockchain::difficulty::RETARGET_INTERVAL;
+use crate::blockchain::timestamp::MEDIAN_TIME_SPAN;
+use crate::consensus::EngineState;
+use crate::Block;
+
+/// Number of the last blocks any rule looks back on.
+pub const LOOKBACK: usize = if RETARGET_INTERVAL > MEDIAN_TIME_SPAN {
+    RETARGET_INTERVAL
+} else {
+    MEDIAN_TIME_SPAN
+};
+
+/// Ancestors of a block to be appended: how many blocks precede it, and the last of them
+/// as far as the rules look back, so that the whole chain need not be copied.
+#[derive(Clone)]
+pub struct Ancestors<'a> {
+    len: usize,
+    recent: Cow<'a, [Block]>,
+    /// State of the consensus engine after the parent, if known.
+    state: EngineState,
+}
+
+impl<'a> Ancestors<'a> {
+    /// Ancestors of the block following `chain`, borrowing its last blocks.
+    /// The state of the consensus engine is not known.
+    pub fn of(chain: &'a [Block]) -> Ancestors<'a> {
+        let start = chain.len().saturating_sub(LOOKBACK);
+        Ancestors {
+            len: chain.len(),
+            recent: Cow::Borrowed(&chain[start..]),
+            state: None,
+        }
+    }
+
+    /// Ancestors of the block following a chain of `len` blocks ending with `recent`,
+    /// after which the consensus engine is in `state`.
+    pub fn new(len: usize, recent: Vec<Block>, state: EngineState) -> Ancestors<'static> {
+        Ancestors {
+            len,
+            recent: Cow::Owned(recent),
+            state,
+        }
+    }
+
+    /// Height of the block following the ancestors,
+    /// i.e. the number of blocks from the genesis block up to its parent.
+    pub fn next_height(&self) -> usize {
+        self.len
+    }
+
+    pub fn last(&self) -> Option<&Block> {
+        self.recent.last()
+    }
+
+    /// The last blocks up to the parent, oldest first. At least `LOOKBACK` of them
+    /// unless the chain is shorter.
+    pub fn recent(&self) -> &[Block] {
+        &self.recent
+    }
+
+    /// State of the consensus engine after the parent.
+    pub fn state(&self) -> &EngineState {
+        &self.state
+    }
+
+    /// Height of the first block of `recent`.
+    pub fn first_height(&self) -> usize {
+        self.len - self.recent.len()
+    }
+}
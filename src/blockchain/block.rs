@@ -1,53 +1,132 @@
-use chrono::Utc;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
+use crate::blockchain::limits;
+use crate::blockchain::merkle::{self, MerkleProof};
+use crate::crypt::keys;
 use crate::crypt::sha::get_double_sha256;
 
-#[derive(Clone, Serialize, Debug)]
-pub struct Block {
-    timestamp: i64,
-    transactions: Vec<String>,
+/// Fields of a block committed to by its hash and by its seal.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BlockHeader {
+    pub timestamp: i64,
+    pub merkle_root: String,
     pub previous_block_hash: Option<String>,
-    nonce: Option<u128>,
+    pub difficulty: usize,
+    pub nonce: Option<u128>,
+    /// Public key of the authority in turn to seal the block. (Proof of Authority)
+    #[serde(default)]
+    pub signer: Option<String>,
+    #[serde(default)]
+    pub signature: Option<String>,
 }
 
-const GENESIS_TXN: &str = "ad9b477b42b22cdf18b1335603d07378ace83561d8398fbfc8de94196c65d806";
+impl BlockHeader {
+    /// Create the header of a block following `previous_block_hash`,
+    /// whose consensus fields are left for the consensus engine to fill.
+    pub fn new(previous_block_hash: String, timestamp: i64) -> BlockHeader {
+        BlockHeader {
+            timestamp,
+            merkle_root: merkle::compute_root(&[]),
+            previous_block_hash: Some(previous_block_hash),
+            difficulty: 0,
+            nonce: None,
+            signer: None,
+            signature: None,
+        }
+    }
 
-impl Block {
-    /// Create a genesis block.
-    pub fn new_genesis() -> Block {
-        let transactions = vec![GENESIS_TXN.to_string()];
-        Block::new(transactions, None)
+    /// String committed to by the seal, i.e. the header without the nonce and the signature.
+    pub fn to_unsealed_string(&self) -> String {
+        let mut header = self.clone();
+        header.nonce = None;
+        header.signature = None;
+        serde_json::to_string(&header).unwrap()
+    }
+
+    /// Hash value identifying the block with this header.
+    pub fn hash(&self) -> String {
+        get_double_sha256(serde_json::to_string(self).unwrap())
     }
 
-    /// Create a common block.
-    pub fn new(transactions: Vec<String>, previous_block_hash: Option<String>) -> Block {
-        println!("{}", Utc::now());
+    /// Check the Merkle proof of a transaction against this header alone. (for light clients)
+    pub fn verify_inclusion(&self, transaction: &str, proof: &MerkleProof) -> bool {
+        merkle::verify_proof(transaction, proof, &self.merkle_root)
+    }
+}
 
-        let mut block = Block {
-            timestamp: Utc::now().timestamp(),
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Block {
+    pub header: BlockHeader,
+    transactions: Vec<String>,
+}
+
+impl Block {
+    /// Create a common block which is not mined yet.
+    pub fn new(
+        transactions: Vec<String>,
+        previous_block_hash: Option<String>,
+        difficulty: usize,
+        timestamp: i64,
+    ) -> Block {
+        let block = Block {
+            header: BlockHeader {
+                timestamp,
+                merkle_root: merkle::compute_root(&transactions),
+                previous_block_hash,
+                difficulty,
+                nonce: None,
+                signer: None,
+                signature: None,
+            },
             transactions,
-            previous_block_hash,
-            nonce: None,
         };
         println!("block: {:#?}", block);
-        let json_block = serde_json::to_string(&block).unwrap();
-        block.nonce = Some(compute_nonce_for_pow(json_block, 5));
         block
     }
-}
 
-/// Proof of Work
-fn compute_nonce_for_pow(msg: String, difficulty: usize) -> u128 {
-    // Nonce
-    // difficultyの数字を増やせば増やすほど、末尾で揃えなければならない桁数が増える
-    let suffix = "0".repeat(difficulty);
-    for nonce in 0u128.. {
-        // 総当たり的に数字を増やして試す
-        let digest = get_double_sha256(format!("{}{}", msg, nonce));
-        if digest.ends_with(&suffix) {
-            return nonce;
+    /// Create a block which is not sealed yet from a header prepared by the consensus engine,
+    /// the coinbase transaction and as many of the transactions as the block limits allow,
+    /// taken in order.
+    pub fn assemble(mut header: BlockHeader, coinbase: String, transactions: Vec<String>) -> Block {
+        let count = Block::count_fitting(&header, &coinbase, &transactions);
+        let mut block_transactions = vec![coinbase];
+        block_transactions.extend(transactions.into_iter().take(count));
+        header.merkle_root = merkle::compute_root(&block_transactions);
+        let block = Block {
+            header,
+            transactions: block_transactions,
+        };
+        println!("block: {:#?}", block);
+        block
+    }
+
+    /// Count how many of the transactions from the front fit in a block with the header
+    /// and the coinbase transaction.
+    pub fn count_fitting(header: &BlockHeader, coinbase: &str, transactions: &[String]) -> usize {
+        // nonceや署名は封印するときに決まるので、最大の長さを見込んで大きさを測る
+        let empty = Block {
+            header: BlockHeader {
+                nonce: Some(u128::MAX),
+                signature: Some("0".repeat(keys::SIGNATURE_HEX_LEN)),
+                ..header.clone()
+            },
+            transactions: vec![coinbase.to_string()],
         };
+        limits::count_fitting_transactions(transactions, empty.serialized_size())
+    }
+
+    /// Size in bytes of the block as it is stored and sent to peers.
+    pub fn serialized_size(&self) -> usize {
+        serde_json::to_vec(self).unwrap().len()
+    }
+
+    pub fn transactions(&self) -> &[String] {
+        &self.transactions
+    }
+
+    /// Build a Merkle proof that `transaction` is included in this block.
+    pub fn get_merkle_proof(&self, transaction: &str) -> Option<MerkleProof> {
+        let index = self.transactions.iter().position(|x| x == transaction)?;
+        merkle::build_proof(&self.transactions, index)
     }
-    panic!("Could not find a nonce");
 }
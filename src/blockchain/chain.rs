@@ -1,51 +1,835 @@
+use anyhow::anyhow;
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 
-use crate::crypt::sha::get_double_sha256;
-use crate::Block;
+use crate::blockchain::ancestors::Ancestors;
+use crate::blockchain::block::BlockHeader;
+use crate::blockchain::ledger::{Ledger, LedgerModel};
+use crate::blockchain::merkle::InclusionProof;
+use crate::blockchain::reward::{self, RewardSpec};
+use crate::blockchain::spec::ChainSpec;
+use crate::blockchain::timestamp::{self, NetworkTime};
+use crate::blockchain::tree::BlockTree;
+use crate::blockchain::validation::{self, InvalidBlock, ValidationError};
+use crate::consensus::finality::Checkpoint;
+use crate::consensus::Consensus;
+use crate::core::clock::Clock;
+use crate::storage::Storage;
+use crate::transaction::utxo::UtxoTransaction;
+use crate::{Block, Transaction};
 
+/// Change of the active chain, notified to subscribers.
 #[derive(Clone, Debug)]
-pub struct Blockchain {
-    #[allow(dead_code)]
+pub enum ChainEvent {
+    /// A block was appended to the tip of the active chain.
+    Extended(Block),
+    /// The active chain switched to a heavier branch.
+    Reorganized {
+        disconnected: Vec<Block>,
+        connected: Vec<Block>,
+    },
+}
+
+pub struct Blockchain<S: Storage> {
     genesis_block: Block,
+    /// Coins allocated by the genesis block.
+    allocated_supply: i64,
+    reward: RewardSpec,
+    /// Active chain, i.e. the branch of `tree` with the most cumulative work.
     chain: Arc<Mutex<Vec<Block>>>,
+    tree: Arc<Mutex<BlockTree>>,
+    subscribers: Arc<Mutex<Vec<Sender<ChainEvent>>>>,
+    storage: Arc<Mutex<S>>,
+    network_time: NetworkTime,
+    consensus: Arc<dyn Consensus>,
+    /// Last block finalized by the validators. The active chain never leaves it.
+    finalized: Arc<Mutex<Checkpoint>>,
+    ledger_model: LedgerModel,
+    /// Ownership of coins at the tip of the active chain.
+    ledger: Arc<Mutex<Ledger>>,
 }
 
-impl Blockchain {
-    pub fn new(genesis_block: Block) -> Blockchain {
-        println!("Initializing Blockchain ...");
+impl<S: Storage> Clone for Blockchain<S> {
+    fn clone(&self) -> Self {
         Blockchain {
+            genesis_block: self.genesis_block.clone(),
+            allocated_supply: self.allocated_supply,
+            reward: self.reward.clone(),
+            chain: self.chain.clone(),
+            tree: self.tree.clone(),
+            subscribers: self.subscribers.clone(),
+            storage: self.storage.clone(),
+            network_time: self.network_time.clone(),
+            consensus: self.consensus.clone(),
+            finalized: self.finalized.clone(),
+            ledger_model: self.ledger_model,
+            ledger: self.ledger.clone(),
+        }
+    }
+}
+
+impl<S: Storage> Blockchain<S> {
+    /// Load the blockchain kept in the storage, or start a new one from the genesis block
+    /// of `spec` if it is empty. Every stored block is validated again.
+    pub fn new(
+        storage: Arc<Mutex<S>>,
+        spec: &ChainSpec,
+        consensus: Arc<dyn Consensus>,
+        clock: Arc<dyn Clock>,
+    ) -> anyhow::Result<Blockchain<S>> {
+        println!("Initializing Blockchain of {} ...", spec.name);
+        let genesis_block = spec.genesis_block();
+        let blocks = storage.lock().unwrap().get_blocks()?;
+        let mut blocks = blocks.into_iter();
+        match blocks.next() {
+            Some(stored) if stored.header.hash() != genesis_block.header.hash() => {
+                return Err(anyhow!(
+                    "stored blockchain starts from another genesis block: {}",
+                    stored.header.hash()
+                ))
+            }
+            Some(_) => {}
+            None => storage.lock().unwrap().put_block(&genesis_block)?,
+        };
+
+        let mut bc = Blockchain {
             genesis_block: genesis_block.clone(),
-            chain: Arc::new(Mutex::new(vec![genesis_block])),
+            allocated_supply: spec.allocated_supply(),
+            reward: spec.reward.clone(),
+            chain: Arc::new(Mutex::new(vec![genesis_block.clone()])),
+            tree: Arc::new(Mutex::new(BlockTree::new(
+                genesis_block.clone(),
+                consensus.block_weight(&genesis_block.header),
+                consensus.next_state(&genesis_block, &None),
+            ))),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            storage,
+            network_time: NetworkTime::new(clock),
+            consensus,
+            finalized: Arc::new(Mutex::new(Checkpoint {
+                height: 0,
+                block_hash: genesis_block.header.hash(),
+            })),
+            ledger_model: spec.ledger,
+            ledger: Arc::new(Mutex::new(Ledger::replay(
+                spec.ledger,
+                std::slice::from_ref(&genesis_block),
+            )?)),
+        };
+        // 保存済みのブロックを書き戻しても、既知のブロックは無視されるので問題ない
+        for block in blocks {
+            bc.set_new_block(block)?;
         }
+        if let Some(checkpoint) = bc.storage.lock().unwrap().get_finalized()? {
+            bc.restore_finalized(checkpoint)?;
+        };
+
+        let tip = bc.get_last_block_hash();
+        let stored_tip = bc.storage.lock().unwrap().get_tip()?;
+        if stored_tip.is_some() && stored_tip != Some(tip.clone()) {
+            println!(
+                "stored chain tip is not the heaviest one, switching to: {}",
+                tip
+            );
+        };
+        bc.storage.lock().unwrap().put_tip(&tip)?;
+        println!("Blockchain was loaded up to height {}", bc.get_height());
+        Ok(bc)
     }
 
-    pub fn set_new_block(&mut self, block: Block) {
-        self.chain.lock().unwrap().push(block);
+    /// Receive every change of the active chain from now on.
+    pub fn subscribe(&self) -> Receiver<ChainEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
     }
 
-    #[allow(dead_code)]
-    pub fn is_valid(&self, chain: Vec<Block>) -> bool {
-        self.chain.lock().unwrap()[1..]
+    fn notify(&self, event: ChainEvent) {
+        // 受信側が終了していたら購読を解除する
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|x| x.send(event.clone()).is_ok());
+    }
+
+    /// Validate a block and add it to the block tree.
+    /// If its branch becomes the heaviest, the active chain is switched to it.
+    pub fn set_new_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        self.add_blocks(vec![block])
+    }
+
+    /// Validate blocks, each following the one before it, and add them to the block tree.
+    /// If their branch becomes the heaviest, the active chain is switched to it.
+    /// Blocks before an invalid one are kept.
+    fn add_blocks(&mut self, blocks: Vec<Block>) -> Result<(), ValidationError> {
+        let mut tree = self.tree.lock().unwrap();
+        let known = blocks
             .iter()
-            .cloned()
-            .enumerate()
-            .any(move |(i, x): (usize, Block)| {
-                self.get_hash(&chain[i]) == x.previous_block_hash.unwrap()
-            })
+            .take_while(|x| tree.contains(&self.get_hash(x)))
+            .count();
+        let mut blocks = blocks.into_iter().skip(known).peekable();
+        let mut parent = match blocks.peek() {
+            Some(block) => block.header.previous_block_hash.clone().unwrap_or_default(),
+            None => {
+                println!("these blocks are already known");
+                return Ok(());
+            }
+        };
+        // 親ブロックを知らなければ、チェーン全体を受け取るまで検証できない
+        let height = match tree.height_of(&parent) {
+            Some(height) => height + 1,
+            None => {
+                return Err(ValidationError {
+                    height: 0,
+                    reason: InvalidBlock::UnknownParent,
+                })
+            }
+        };
+        // 確定済みのブロックより前で分かれる枝には移れないし、台帳もそこまで戻せない
+        if !tree.descends_from(&parent, &self.get_finalized().block_hash) {
+            return Err(ValidationError {
+                height,
+                reason: InvalidBlock::ConflictsWithFinalized,
+            });
+        }
+
+        // 先端の台帳を親の時点まで動かし、1つずつ接続しながら検証する
+        let mut chain = self.chain.lock().unwrap();
+        let mut ledger = self.ledger.lock().unwrap();
+        let tip = self.get_hash(chain.last().unwrap());
+        Self::move_ledger(&tree, &mut ledger, &tip, &parent);
+        let mut result = Ok(());
+        let mut last_hash = None;
+        for block in blocks {
+            let ancestors = tree.ancestors(&parent);
+            if let Err(reason) = validation::validate_block(
+                &block,
+                &ancestors,
+                self.network_time.now(),
+                self.consensus.as_ref(),
+                &self.reward,
+                &mut ledger,
+            ) {
+                result = Err(ValidationError {
+                    height: ancestors.next_height(),
+                    reason,
+                });
+                break;
+            }
+            let weight = self.consensus.block_weight(&block.header);
+            let state = self.consensus.next_state(&block, ancestors.state());
+            parent = tree.insert(block.clone(), weight, state);
+            self.store(|x| x.put_block(&block));
+            last_hash = Some(parent.clone());
+        }
+
+        // 台帳は最後に接続できたブロックの時点にあるので、新しい先端まで動かす
+        let event = self.update_active_chain(&tree, &mut chain);
+        let tip = self.get_hash(chain.last().unwrap());
+        Self::move_ledger(&tree, &mut ledger, &parent, &tip);
+        drop(ledger);
+        drop(chain);
+        drop(tree);
+        match (last_hash, event) {
+            (Some(_), Some(event)) => self.notify(event),
+            (Some(hash), None) => println!("block was added to a side branch: {}", hash),
+            (None, _) => {}
+        };
+        result
+    }
+
+    /// Move a ledger at the block `from` of the tree to the block `to`, undoing the
+    /// blocks of `from`'s branch down to their fork point and connecting those of `to`'s.
+    fn move_ledger(tree: &BlockTree, ledger: &mut Ledger, from: &str, to: &str) {
+        let (disconnected, connected) = tree.route(from, to);
+        for block in disconnected {
+            ledger.disconnect_block(block);
+        }
+        for block in connected {
+            ledger
+                .connect_block(block)
+                .expect("validated block must connect to the ledger");
+        }
+    }
+
+    /// Switch the active chain to the best branch of the tree which contains the
+    /// finalized block, and return the change if there was one.
+    /// The tree lock held by the caller keeps `finalize` from moving the checkpoint meanwhile.
+    /// The ledger is left for the caller to move.
+    fn update_active_chain(&self, tree: &BlockTree, chain: &mut Vec<Block>) -> Option<ChainEvent> {
+        let finalized = self.get_finalized().block_hash;
+        let current_tip = self.get_hash(chain.last().unwrap());
+        let best_tip = tree.best_tip(&current_tip, &finalized, |a, b| {
+            self.consensus.compare_chains(a, b)
+        });
+        if best_tip == current_tip {
+            return None;
+        }
+        self.switch_to(tree, chain, &best_tip)
+    }
+
+    /// Make the branch of the tree ending at `best_tip` the active chain,
+    /// unless it does not contain the finalized block.
+    fn switch_to(
+        &self,
+        tree: &BlockTree,
+        chain: &mut Vec<Block>,
+        best_tip: &str,
+    ) -> Option<ChainEvent> {
+        let finalized = self.get_finalized().block_hash;
+        if !tree.descends_from(best_tip, &finalized) {
+            eprintln!(
+                "branch ending at {} does not contain the finalized block {}",
+                best_tip, finalized
+            );
+            return None;
+        }
+        let current_tip = self.get_hash(chain.last().unwrap());
+        let (disconnected, connected) = tree.route(&current_tip, best_tip);
+        let event = if disconnected.is_empty() && connected.len() == 1 {
+            chain.push(connected[0].clone());
+            ChainEvent::Extended(connected[0].clone())
+        } else {
+            // 分岐点より後のブロックだけを入れ替える
+            println!(
+                "Chain reorganization: {} blocks disconnected, {} blocks connected",
+                disconnected.len(),
+                connected.len()
+            );
+            let disconnected = chain.split_off(chain.len() - disconnected.len());
+            let connected: Vec<Block> = connected.into_iter().cloned().collect();
+            chain.extend(connected.iter().cloned());
+            ChainEvent::Reorganized {
+                disconnected,
+                connected,
+            }
+        };
+        self.store(|x| x.put_tip(best_tip));
+        Some(event)
+    }
+
+    /// Write through to the storage. A failure is only reported since the node can keep
+    /// running with the state in memory.
+    fn store(&self, f: impl FnOnce(&mut S) -> anyhow::Result<()>) {
+        if let Err(e) = f(&mut self.storage.lock().unwrap()) {
+            eprintln!("Failed to write to the storage: {}", e);
+        };
+    }
+
+    /// Add the blocks of a chain received from a peer.
+    /// The active chain is switched if the received one has more work.
+    pub fn merge_chain(&mut self, new_chain: Vec<Block>) -> Result<(), ValidationError> {
+        match new_chain.first() {
+            Some(first) if self.get_hash(first) == self.get_hash(&self.genesis_block) => {}
+            _ => {
+                return Err(ValidationError {
+                    height: 0,
+                    reason: InvalidBlock::GenesisMismatch,
+                })
+            }
+        };
+        self.add_blocks(new_chain.into_iter().skip(1).collect())
+    }
+
+    /// Mark a block of the active chain as final, so that it can never be reverted.
+    /// Return the new finalized height.
+    pub fn finalize(&self, block_hash: &str) -> anyhow::Result<usize> {
+        // 最良の枝を選んで切り替える間はツリーのロックが持たれているので、
+        // それを取ってから更新すれば、確定と同時に再編成が起きることはない
+        let tree = self.tree.lock().unwrap();
+        let chain = self.chain.lock().unwrap();
+        let height = Self::active_height_of(&tree, &chain, block_hash).ok_or_else(|| {
+            anyhow!(
+                "block to finalize is not on the active chain: {}",
+                block_hash
+            )
+        })?;
+        let mut ledger = self.ledger.lock().unwrap();
+        let mut finalized = self.finalized.lock().unwrap();
+        // 既にそれより後のブロックが確定していれば何もしない
+        if height > finalized.height {
+            *finalized = Checkpoint {
+                height,
+                block_hash: block_hash.to_string(),
+            };
+            self.store(|x| x.put_finalized(&finalized));
+            ledger.prune(height);
+        }
+        Ok(finalized.height)
+    }
+
+    /// Put back the finalized block stored before a restart, and leave any branch
+    /// which does not contain it.
+    fn restore_finalized(&self, checkpoint: Checkpoint) -> anyhow::Result<()> {
+        let tree = self.tree.lock().unwrap();
+        if !tree.contains(&checkpoint.block_hash) {
+            return Err(anyhow!(
+                "finalized block is not stored: {}",
+                checkpoint.block_hash
+            ));
+        }
+        println!(
+            "Finalized height is ... {} ({})",
+            checkpoint.height, checkpoint.block_hash
+        );
+        *self.finalized.lock().unwrap() = checkpoint;
+        // 確定済みのブロックを含まない枝が選ばれていれば、含む枝のうち最良のものに移る
+        let mut chain = self.chain.lock().unwrap();
+        let finalized = self.get_finalized();
+        let on_active_chain = chain
+            .get(finalized.height)
+            .is_some_and(|x| self.get_hash(x) == finalized.block_hash);
+        if !on_active_chain {
+            let best_tip = tree.best_tip(&finalized.block_hash, &finalized.block_hash, |a, b| {
+                self.consensus.compare_chains(a, b)
+            });
+            let old_tip = self.get_hash(chain.last().unwrap());
+            self.switch_to(&tree, &mut chain, &best_tip);
+            let tip = self.get_hash(chain.last().unwrap());
+            Self::move_ledger(&tree, &mut self.ledger.lock().unwrap(), &old_tip, &tip);
+        }
+        self.ledger.lock().unwrap().prune(finalized.height);
+        Ok(())
+    }
+
+    /// Last block finalized by the validators, or the genesis block.
+    pub fn get_finalized(&self) -> Checkpoint {
+        self.finalized.lock().unwrap().clone()
+    }
+
+    /// Consensus engine this chain follows, which also seals the blocks of this node.
+    pub fn get_consensus(&self) -> Arc<dyn Consensus> {
+        self.consensus.clone()
+    }
+
+    /// Build a block on top of the active chain which is ready to be sealed, whose coinbase
+    /// pays the subsidy and the fees of the included transactions to `miner_address`.
+    /// Transactions which are invalid on the ledger, and those beyond the block limits,
+    /// are left out. So are the transactions from the first one whose fee would make
+    /// the coinbase value too large.
+    pub fn prepare_block(&self, miner_address: &str, transactions: Vec<String>) -> Block {
+        let timestamp = self.get_next_timestamp();
+        let tree = self.tree.lock().unwrap();
+        let chain = self.chain.lock().unwrap();
+        let parent = self.get_hash(chain.last().unwrap());
+        let ancestors = tree.ancestors(&parent);
+        drop(tree);
+        let mut header = BlockHeader::new(parent, timestamp);
+        self.consensus.prepare_header(&mut header, &ancestors);
+        let mut block_transactions = self.consensus.prepare_transactions(&ancestors);
+        let mut fees = vec![0; block_transactions.len()];
+        let subsidy = i64::from(reward::block_subsidy(&self.reward, chain.len()));
+        let mut reward = subsidy;
+        // 台帳は複製せず、取り込む取引による変更だけを上に重ねる
+        let ledger = self.ledger.lock().unwrap();
+        let mut draft = ledger.draft();
+        for txn in transactions {
+            if let Some(fee) = draft.apply_transaction(&txn) {
+                // 下書きには適用済みなので、飛ばさずにここで打ち切る
+                if reward + fee > i64::from(i32::MAX) {
+                    println!("fees of the remaining transactions exceed the coinbase value");
+                    break;
+                }
+                reward += fee;
+                block_transactions.push(txn);
+                fees.push(fee);
+            } else {
+                println!(
+                    "this transaction cannot be applied to the ledger yet: {}",
+                    txn
+                );
+            }
+        }
+        let coinbase = |fees: &[i64]| {
+            let value = i32::try_from(subsidy + fees.iter().sum::<i64>())
+                .expect("fees were limited to fit in a coinbase transaction");
+            Transaction::new_coinbase(miner_address, value).to_string()
+        };
+        // 全部の手数料を含めたcoinbaseが一番長いので、それで上限に収まる数を見積もる
+        let count = Block::count_fitting(&header, &coinbase(&fees), &block_transactions);
+        block_transactions.truncate(count);
+        fees.truncate(count);
+        Block::assemble(header, coinbase(&fees), block_transactions)
     }
 
-    #[allow(dead_code)]
-    pub fn is_invalid(&self, chain: Vec<Block>) -> bool {
-        !self.is_valid(chain)
+    /// Balance of an address at the given height of the active chain, or at its tip.
+    pub fn get_balance(&self, address: &str, height: Option<usize>) -> Option<i64> {
+        let chain = self.chain.lock().unwrap();
+        let end = match height {
+            Some(height) if height >= chain.len() => return None,
+            Some(height) if height + 1 < chain.len() => height + 1,
+            _ => return Some(self.ledger.lock().unwrap().get_balance(address)),
+        };
+        // 過去の高さの残高はチェーンを再生して求める
+        let ledger =
+            Ledger::replay(self.ledger_model, &chain[..end]).expect("active chain was validated");
+        Some(ledger.get_balance(address))
+    }
+
+    /// Nonce the next transaction sent from the address must have at the tip of the active chain.
+    pub fn get_next_nonce(&self, address: &str) -> u64 {
+        self.ledger.lock().unwrap().get_next_nonce(address)
+    }
+
+    /// How this chain keeps track of who owns which coins.
+    pub fn get_ledger_model(&self) -> LedgerModel {
+        self.ledger_model
+    }
+
+    /// Check a UTXO transaction against the unspent outputs at the tip of the active chain.
+    pub fn check_utxo_transaction(&self, txn: &UtxoTransaction) -> anyhow::Result<()> {
+        match &*self.ledger.lock().unwrap() {
+            Ledger::Utxo(utxos) => utxos.check_transaction(txn),
+            Ledger::Account(_) => Err(anyhow!("this chain uses the account ledger")),
+        }
+    }
+
+    /// Total number of coins issued up to the given height of the active chain,
+    /// including those allocated by the genesis block.
+    pub fn get_issued_supply(&self, height: usize) -> Option<i64> {
+        if height > self.get_height() {
+            return None;
+        }
+        Some(self.allocated_supply + reward::issued_supply(&self.reward, height))
+    }
+
+    /// Clock of this node adjusted by the clocks of its peers.
+    pub fn get_network_time(&self) -> NetworkTime {
+        self.network_time.clone()
+    }
+
+    /// Timestamp for a block mined now on top of the chain.
+    /// It must be newer than the median time past even if our clock is behind.
+    pub fn get_next_timestamp(&self) -> i64 {
+        let median = timestamp::median_time_past(&Ancestors::of(&self.chain.lock().unwrap()));
+        self.network_time.now().max(median + 1)
+    }
+
+    /// Hash of the genesis block, which identifies the network of this chain.
+    pub fn get_genesis_hash(&self) -> String {
+        self.genesis_block.header.hash()
+    }
+
+    /// Hash of the last block, to which the next block is linked.
+    pub fn get_last_block_hash(&self) -> String {
+        self.get_hash(self.chain.lock().unwrap().last().unwrap())
+    }
+
+    /// Return the block with the given hash, including blocks on side branches.
+    pub fn get_block_by_hash(&self, hash: &str) -> Option<Block> {
+        self.tree.lock().unwrap().get(hash).cloned()
+    }
+
+    /// Height of the block with the given hash if it is on the active chain.
+    pub fn get_height_of(&self, hash: &str) -> Option<usize> {
+        let tree = self.tree.lock().unwrap();
+        let chain = self.chain.lock().unwrap();
+        Self::active_height_of(&tree, &chain, hash)
+    }
+
+    /// Look up the height of a block in the tree, and check that the active chain has it there.
+    fn active_height_of(tree: &BlockTree, chain: &[Block], hash: &str) -> Option<usize> {
+        let height = tree.height_of(hash)?;
+        chain
+            .get(height)
+            .filter(|x| x.header.hash() == hash)
+            .map(|_| height)
+    }
+
+    /// Return the block of the active chain at the given height.
+    pub fn get_block_by_height(&self, height: usize) -> Option<Block> {
+        self.chain.lock().unwrap().get(height).cloned()
+    }
+
+    /// Return the last block of the active chain.
+    pub fn get_tip(&self) -> Block {
+        self.chain.lock().unwrap().last().unwrap().clone()
+    }
+
+    /// Height of the last block of the active chain. The genesis block is at height 0.
+    pub fn get_height(&self) -> usize {
+        self.chain.lock().unwrap().len() - 1
+    }
+
+    /// Return the headers of the active chain in the given range of heights.
+    pub fn get_headers(&self, heights: Range<usize>) -> Vec<BlockHeader> {
+        let chain = self.chain.lock().unwrap();
+        let end = heights.end.min(chain.len());
+        let start = heights.start.min(end);
+        chain[start..end].iter().map(|x| x.header.clone()).collect()
     }
 
     /// 正当性確認に使うためブロックのハッシュ値を取る
+    /// トランザクションはMerkle rootを通してのみハッシュ値に反映される
     pub fn get_hash(&self, block: &Block) -> String {
-        let block_string = serde_json::to_string(block).unwrap();
-        get_double_sha256(block_string)
+        block.header.hash()
     }
 
     pub fn get_chain(&self) -> Vec<Block> {
         self.chain.lock().unwrap().clone()
     }
+
+    /// Build an inclusion proof for `transaction` in the block with the given hash.
+    pub fn get_inclusion_proof(
+        &self,
+        block_hash: &str,
+        transaction: &str,
+    ) -> Option<InclusionProof> {
+        let block = self.get_block_by_hash(block_hash)?;
+        Some(InclusionProof {
+            block_hash: block_hash.to_string(),
+            header: block.header.clone(),
+            proof: block.get_merkle_proof(transaction)?,
+        })
+    }
+
+    /// Search the chain for the block containing `transaction` and build its inclusion proof.
+    pub fn find_inclusion_proof(&self, transaction: &str) -> Option<InclusionProof> {
+        let chain = self.chain.lock().unwrap();
+        chain.iter().find_map(|block| {
+            Some(InclusionProof {
+                block_hash: self.get_hash(block),
+                header: block.header.clone(),
+                proof: block.get_merkle_proof(transaction)?,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::ancestors::LOOKBACK;
+    use crate::blockchain::limits::MAX_BLOCK_SIZE;
+    use crate::blockchain::spec::Allocation;
+    use crate::consensus::{ConsensusSpec, SealerConfig};
+    use crate::core::clock::MockClock;
+    use crate::crypt::keys::KeyPair;
+    use crate::storage::memory::MemoryStore;
+    use crate::transaction::pool::TransactionPool;
+
+    fn new_chain(key: &KeyPair, storage: Arc<Mutex<MemoryStore>>) -> Blockchain<MemoryStore> {
+        new_chain_with_spec(key, storage, ChainSpec::default())
+    }
+
+    /// Chain of `spec` whose blocks are signed by `key`.
+    fn new_chain_with_spec(
+        key: &KeyPair,
+        storage: Arc<Mutex<MemoryStore>>,
+        spec: ChainSpec,
+    ) -> Blockchain<MemoryStore> {
+        let spec = ChainSpec {
+            consensus: ConsensusSpec::ProofOfAuthority {
+                signers: vec![key.public_key()],
+            },
+            ..spec
+        };
+        let config = SealerConfig {
+            signer_key: Some(key.clone()),
+            votes: Vec::new(),
+        };
+        let consensus = spec.consensus.build(config).unwrap();
+        let clock = Arc::new(MockClock::new(spec.genesis.timestamp + 60));
+        Blockchain::new(storage, &spec, consensus, clock).unwrap()
+    }
+
+    fn mine(bc: &mut Blockchain<MemoryStore>, miner: &str, count: usize) {
+        for _ in 0..count {
+            let mut block = bc.prepare_block(miner, Vec::new());
+            assert!(bc.get_consensus().seal(&mut block));
+            bc.set_new_block(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn heavier_branch_replaces_the_active_chain_and_its_ledger() {
+        let key = KeyPair::from_seed("signer");
+        let mut bc = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        let mut other = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        mine(&mut bc, "a", 1);
+        mine(&mut other, "b", 2);
+        let subsidy = i64::from(reward::block_subsidy(&RewardSpec::default(), 1));
+        assert_eq!(bc.get_balance("a", None), Some(subsidy));
+
+        bc.merge_chain(other.get_chain()).unwrap();
+        assert_eq!(bc.get_last_block_hash(), other.get_last_block_hash());
+        assert_eq!(bc.get_balance("a", None), Some(0));
+        assert_eq!(bc.get_balance("b", None), Some(subsidy * 2));
+
+        // 負けた枝の先にブロックが来たら、分岐点からの台帳で検証される
+        let mut side = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        side.merge_chain(other.get_chain()[..2].to_vec()).unwrap();
+        mine(&mut side, "c", 2);
+        bc.merge_chain(side.get_chain()).unwrap();
+        assert_eq!(bc.get_height(), 3);
+        assert_eq!(bc.get_balance("b", None), Some(subsidy));
+        assert_eq!(bc.get_balance("c", None), Some(subsidy * 2));
+    }
+
+    #[test]
+    fn heavier_branch_without_the_finalized_block_is_not_followed() {
+        let key = KeyPair::from_seed("signer");
+        let mut bc = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        let mut other = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        mine(&mut bc, "a", 1);
+        mine(&mut other, "b", 3);
+        let tip = bc.get_last_block_hash();
+        assert_eq!(bc.finalize(&tip).unwrap(), 1);
+
+        assert_eq!(
+            bc.merge_chain(other.get_chain()).unwrap_err(),
+            ValidationError {
+                height: 1,
+                reason: InvalidBlock::ConflictsWithFinalized
+            }
+        );
+        assert_eq!(bc.get_last_block_hash(), tip);
+        assert_eq!(bc.get_balance("b", None), Some(0));
+    }
+
+    #[test]
+    fn coinbase_pays_the_subsidy_of_the_chain_spec() {
+        let key = KeyPair::from_seed("signer");
+        let reward = RewardSpec {
+            initial_subsidy: 7,
+            halving_interval: 2,
+            max_supply: 100,
+        };
+        let spec = ChainSpec {
+            reward,
+            ..ChainSpec::default()
+        };
+        let mut bc = new_chain_with_spec(&key, Arc::new(Mutex::new(MemoryStore::new())), spec);
+        mine(&mut bc, "a", 2);
+        assert_eq!(bc.get_balance("a", None), Some(7 + 3));
+        assert_eq!(bc.get_issued_supply(2), Some(bc.allocated_supply + 10));
+
+        let prepared = bc.prepare_block("a", Vec::new());
+        let coinbase = Transaction::new_coinbase("a", 50).to_string();
+        let mut block = Block::assemble(prepared.header, coinbase, Vec::new());
+        assert!(bc.get_consensus().seal(&mut block));
+        assert_eq!(
+            bc.set_new_block(block).unwrap_err().reason,
+            InvalidBlock::CoinbaseValueMismatch {
+                expected: 3,
+                actual: 50
+            }
+        );
+        // 検証のために接続したブロックは台帳から外されている
+        assert_eq!(bc.get_balance("a", None), Some(7 + 3));
+    }
+
+    #[test]
+    fn transactions_beyond_the_block_size_stay_in_the_pool() {
+        let key = KeyPair::from_seed("signer");
+        let mut bc = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        let mut tp = TransactionPool::new(Arc::new(Mutex::new(MemoryStore::new())));
+        let alice = KeyPair::from_seed("alice");
+        // 署名の検証は遅いので、宛先を長くして少ない数で上限に届かせる
+        let recipient = "b".repeat(5_000);
+        let payment = |nonce| Transaction::new_signed(&alice, &recipient, 1, 1, nonce);
+        let count = 30;
+        for nonce in 0..count {
+            assert!(tp.set_new_transaction(payment(nonce)));
+        }
+
+        let candidates = tp.get_block_candidates(|x| bc.get_next_nonce(x)).unwrap();
+        let mut block = bc.prepare_block("miner", candidates);
+        assert!(bc.get_consensus().seal(&mut block));
+        assert!(block.serialized_size() <= MAX_BLOCK_SIZE);
+        let included = block.transactions().len() as u64 - 1;
+        assert!(0 < included && included < count);
+        bc.set_new_block(block.clone()).unwrap();
+        tp.remove_included(block.transactions());
+
+        // 入りきらなかった分は、続きのnonceから次のブロックに回る
+        let rest = tp.get_block_candidates(|x| bc.get_next_nonce(x)).unwrap();
+        assert_eq!(rest.len() as u64, count - included);
+        assert_eq!(rest[0], payment(included).to_string());
+    }
+
+    #[test]
+    fn fees_overflowing_the_coinbase_value_are_left_out() {
+        let key = KeyPair::from_seed("signer");
+        let (alice, bob) = (KeyPair::from_seed("alice"), KeyPair::from_seed("bob"));
+        let mut spec = ChainSpec::default();
+        spec.genesis.allocations = [&alice, &bob]
+            .iter()
+            .map(|x| Allocation {
+                address: x.address(),
+                value: i32::MAX,
+            })
+            .collect();
+        let mut bc = new_chain_with_spec(&key, Arc::new(Mutex::new(MemoryStore::new())), spec);
+        let transactions = vec![
+            Transaction::new_signed(&alice, "carol", 1, 1000, 0).to_string(),
+            Transaction::new_signed(&bob, "carol", 1, i32::MAX - 10, 0).to_string(),
+            Transaction::new_signed(&alice, "carol", 1, 1, 1).to_string(),
+        ];
+
+        let mut block = bc.prepare_block("miner", transactions.clone());
+        assert_eq!(block.transactions()[1..], transactions[..1]);
+        assert!(bc.get_consensus().seal(&mut block));
+        bc.set_new_block(block).unwrap();
+        let subsidy = i64::from(reward::block_subsidy(&RewardSpec::default(), 1));
+        assert_eq!(bc.get_balance("miner", None), Some(subsidy + 1000));
+    }
+
+    #[test]
+    fn stored_blocks_are_loaded_again() {
+        let key = KeyPair::from_seed("signer");
+        let storage = Arc::new(Mutex::new(MemoryStore::new()));
+        let mut bc = new_chain(&key, storage.clone());
+        // 検証に使う祖先の範囲より長くする
+        mine(&mut bc, "a", LOOKBACK + 5);
+        let reloaded = new_chain(&key, storage);
+        assert_eq!(reloaded.get_last_block_hash(), bc.get_last_block_hash());
+        assert_eq!(reloaded.get_balance("a", None), bc.get_balance("a", None));
+    }
+
+    #[test]
+    fn blocks_of_the_active_chain_are_looked_up_by_height() {
+        let key = KeyPair::from_seed("signer");
+        let mut bc = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        let mut other = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        mine(&mut bc, "a", 3);
+        mine(&mut other, "b", 1);
+        bc.merge_chain(other.get_chain()).unwrap();
+        let chain = bc.get_chain();
+        let hashes = |headers: Vec<BlockHeader>| -> Vec<String> {
+            headers.iter().map(|x| x.hash()).collect()
+        };
+
+        assert_eq!(
+            bc.get_block_by_height(2).map(|x| bc.get_hash(&x)),
+            Some(bc.get_hash(&chain[2]))
+        );
+        assert!(bc.get_block_by_height(4).is_none());
+        assert_eq!(bc.get_height_of(&bc.get_hash(&chain[2])), Some(2));
+        // 横の枝のブロックは木にはあるが、メインチェーン上の高さはない
+        let side = other.get_last_block_hash();
+        assert!(bc.get_block_by_hash(&side).is_some());
+        assert_eq!(bc.get_height_of(&side), None);
+
+        assert_eq!(
+            hashes(bc.get_headers(1..3)),
+            [bc.get_hash(&chain[1]), bc.get_hash(&chain[2])]
+        );
+        assert_eq!(hashes(bc.get_headers(3..10)), [bc.get_hash(&chain[3])]);
+        assert!(bc.get_headers(5..9).is_empty());
+    }
+
+    #[test]
+    fn block_on_an_unknown_parent_is_rejected() {
+        let key = KeyPair::from_seed("signer");
+        let mut bc = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        let mut other = new_chain(&key, Arc::new(Mutex::new(MemoryStore::new())));
+        mine(&mut other, "b", 2);
+        let orphan = other.get_chain()[2].clone();
+        assert_eq!(
+            bc.set_new_block(orphan).unwrap_err().reason,
+            InvalidBlock::UnknownParent
+        );
+    }
 }
@@ -0,0 +1,103 @@
+use crate::blockchain::ancestors::Ancestors;
+
+/// Difficulty of the genesis block.
+pub const INITIAL_DIFFICULTY: usize = 5;
+/// Difficulty never falls below this value.
+pub const MIN_DIFFICULTY: usize = 1;
+/// Difficulty is recomputed every this number of blocks.
+pub const RETARGET_INTERVAL: usize = 10;
+/// Block interval in seconds that retargeting aims for.
+const TARGET_BLOCK_INTERVAL: i64 = 10;
+
+/// Compute the difficulty the block following `chain` must have.
+pub fn expected_difficulty(chain: &Ancestors) -> usize {
+    let last = match chain.last() {
+        Some(last) => last,
+        None => return INITIAL_DIFFICULTY,
+    };
+    let height = chain.next_height();
+    if !height.is_multiple_of(RETARGET_INTERVAL) {
+        return last.header.difficulty;
+    }
+
+    // difficultyは末尾で揃える桁数なので、1段階変えると採掘時間は16倍変わる。
+    // 目標の半分未満、あるいは2倍を超えたときにだけ1段階ずつ調整する
+    let recent = chain.recent();
+    let first = &recent[recent.len() - RETARGET_INTERVAL];
+    let actual_timespan = last.header.timestamp - first.header.timestamp;
+    let target_timespan = TARGET_BLOCK_INTERVAL * (RETARGET_INTERVAL as i64 - 1);
+    if actual_timespan < target_timespan / 2 {
+        last.header.difficulty + 1
+    } else if actual_timespan > target_timespan * 2 {
+        last.header.difficulty.saturating_sub(1).max(MIN_DIFFICULTY)
+    } else {
+        last.header.difficulty
+    }
+}
+
+/// Expected number of hashes needed to mine a block with the given difficulty.
+pub fn block_work(difficulty: usize) -> u128 {
+    // 16進数で末尾1桁を0に揃えるごとに、必要な試行回数は16倍になる
+    16u128.saturating_pow(difficulty as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+
+    /// Chain of `len` blocks with the given difficulty, mined every `interval` seconds.
+    fn chain(len: usize, difficulty: usize, interval: i64) -> Vec<Block> {
+        (0..len)
+            .map(|i| Block::new(Vec::new(), None, difficulty, i as i64 * interval))
+            .collect()
+    }
+
+    #[test]
+    fn difficulty_is_kept_between_retargets() {
+        assert_eq!(expected_difficulty(&Ancestors::of(&[])), INITIAL_DIFFICULTY);
+        assert_eq!(expected_difficulty(&Ancestors::of(&chain(5, 3, 1))), 3);
+        assert_eq!(expected_difficulty(&Ancestors::of(&chain(15, 3, 100))), 3);
+    }
+
+    #[test]
+    fn difficulty_rises_when_blocks_come_too_fast() {
+        assert_eq!(
+            expected_difficulty(&Ancestors::of(&chain(RETARGET_INTERVAL, 3, 1))),
+            4
+        );
+    }
+
+    #[test]
+    fn difficulty_falls_when_blocks_come_too_slow() {
+        assert_eq!(
+            expected_difficulty(&Ancestors::of(&chain(RETARGET_INTERVAL, 3, 100))),
+            2
+        );
+    }
+
+    #[test]
+    fn difficulty_is_kept_near_the_target_interval() {
+        let interval = TARGET_BLOCK_INTERVAL;
+        assert_eq!(
+            expected_difficulty(&Ancestors::of(&chain(RETARGET_INTERVAL, 3, interval))),
+            3
+        );
+    }
+
+    #[test]
+    fn difficulty_never_falls_below_the_minimum() {
+        assert_eq!(
+            expected_difficulty(&Ancestors::of(&chain(
+                RETARGET_INTERVAL,
+                MIN_DIFFICULTY,
+                100
+            ))),
+            MIN_DIFFICULTY
+        );
+        assert_eq!(
+            expected_difficulty(&Ancestors::of(&chain(RETARGET_INTERVAL, 0, 100))),
+            MIN_DIFFICULTY
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::anyhow;
+use clap::ValueEnum;
+use std::io::{BufRead, Write};
+
+use crate::Block;
+
+/// Magic bytes and version at the head of a binary chain file.
+const BINARY_MAGIC: &[u8; 4] = b"MCHN";
+const BINARY_VERSION: u8 = 1;
+
+/// File format of an exported blockchain.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ChainFormat {
+    /// One JSON-encoded block per line
+    Jsonl,
+    /// Compact binary encoding
+    Binary,
+}
+
+/// Write the blocks in the given format.
+pub fn export_chain(
+    blocks: &[Block],
+    format: ChainFormat,
+    mut writer: impl Write,
+) -> anyhow::Result<()> {
+    match format {
+        ChainFormat::Jsonl => {
+            for block in blocks {
+                serde_json::to_writer(&mut writer, block)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        ChainFormat::Binary => {
+            writer.write_all(BINARY_MAGIC)?;
+            writer.write_all(&[BINARY_VERSION])?;
+            for block in blocks {
+                bincode::serialize_into(&mut writer, block)?;
+            }
+        }
+    };
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read blocks written by `export_chain`. The blocks are not validated here.
+pub fn import_chain(format: ChainFormat, mut reader: impl BufRead) -> anyhow::Result<Vec<Block>> {
+    let mut blocks = Vec::new();
+    match format {
+        ChainFormat::Jsonl => {
+            for (i, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let block = serde_json::from_str(&line)
+                    .map_err(|e| anyhow!("line {} is not a block: {}", i + 1, e))?;
+                blocks.push(block);
+            }
+        }
+        ChainFormat::Binary => {
+            let mut head = [0; 5];
+            reader.read_exact(&mut head)?;
+            if &head[..4] != BINARY_MAGIC || head[4] != BINARY_VERSION {
+                return Err(anyhow!(
+                    "not a binary chain file of version {}",
+                    BINARY_VERSION
+                ));
+            }
+            while !reader.fill_buf()?.is_empty() {
+                blocks.push(bincode::deserialize_from(&mut reader)?);
+            }
+        }
+    };
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::Transaction;
+
+    fn blocks() -> Vec<Block> {
+        let genesis = ChainSpec::default().genesis_block();
+        let coinbase = Transaction::new_coinbase("miner", 50).to_string();
+        let block = Block::new(
+            vec![coinbase],
+            Some(genesis.header.hash()),
+            genesis.header.difficulty,
+            genesis.header.timestamp + 10,
+        );
+        vec![genesis, block]
+    }
+
+    fn export(format: ChainFormat) -> Vec<u8> {
+        let mut buf = Vec::new();
+        export_chain(&blocks(), format, &mut buf).unwrap();
+        buf
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<String> {
+        blocks.iter().map(|x| x.header.hash()).collect()
+    }
+
+    #[test]
+    fn jsonl_round_trip() {
+        let imported = import_chain(ChainFormat::Jsonl, &export(ChainFormat::Jsonl)[..]).unwrap();
+        assert_eq!(hashes(&imported), hashes(&blocks()));
+        assert_eq!(imported[1].transactions(), blocks()[1].transactions());
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let imported = import_chain(ChainFormat::Binary, &export(ChainFormat::Binary)[..]).unwrap();
+        assert_eq!(hashes(&imported), hashes(&blocks()));
+        assert_eq!(imported[1].transactions(), blocks()[1].transactions());
+    }
+
+    #[test]
+    fn binary_file_with_another_magic_is_rejected() {
+        let mut data = export(ChainFormat::Binary);
+        data[0] = b'X';
+        assert!(import_chain(ChainFormat::Binary, &data[..]).is_err());
+        // JSONLのファイルをバイナリとして読んでも受け付けない
+        assert!(import_chain(ChainFormat::Binary, &export(ChainFormat::Jsonl)[..]).is_err());
+    }
+
+    #[test]
+    fn truncated_binary_file_is_rejected() {
+        let data = export(ChainFormat::Binary);
+        assert!(import_chain(ChainFormat::Binary, &data[..data.len() - 1]).is_err());
+        assert!(import_chain(ChainFormat::Binary, &data[..3]).is_err());
+    }
+
+    #[test]
+    fn line_which_is_not_a_block_is_rejected() {
+        let mut data = export(ChainFormat::Jsonl);
+        data.extend(b"{\"header\":\n");
+        assert!(import_chain(ChainFormat::Jsonl, &data[..]).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::utxo_set::{self, UtxoSet};
+use crate::blockchain::validation::{InvalidBlock, ValidationError};
+use crate::blockchain::world_state::{self, WorldState};
+use crate::transaction::utxo::UtxoTransaction;
+use crate::{Block, Transaction};
+
+/// Check whether the transaction at position `i` of a block may be a coinbase transaction,
+/// i.e. it is the head of the block or an allocation of the genesis block.
+pub fn is_coinbase_position(block: &Block, i: usize) -> bool {
+    i == 0 || block.header.previous_block_hash.is_none()
+}
+
+/// How a network keeps track of who owns which coins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerModel {
+    /// Balances of addresses, moved by `Transaction`s.
+    #[default]
+    Account,
+    /// Unspent outputs, spent by `UtxoTransaction`s.
+    Utxo,
+}
+
+impl LedgerModel {
+    /// Check the form of a transaction of a block other than the coinbase and votes.
+    pub fn is_well_formed(&self, txn: &str) -> bool {
+        match self {
+            LedgerModel::Account => {
+                serde_json::from_str::<Transaction>(txn).is_ok_and(|x| x.is_well_formed())
+            }
+            LedgerModel::Utxo => {
+                serde_json::from_str::<UtxoTransaction>(txn).is_ok_and(|x| x.is_well_formed())
+            }
+        }
+    }
+}
+
+/// Ownership of coins at some block of a chain, in the model of the network.
+#[derive(Debug)]
+pub enum Ledger {
+    Account(WorldState),
+    Utxo(UtxoSet),
+}
+
+impl Ledger {
+    /// Replay the blocks of `chain` from the genesis block.
+    pub fn replay(model: LedgerModel, chain: &[Block]) -> Result<Ledger, ValidationError> {
+        let mut ledger = match model {
+            LedgerModel::Account => Ledger::Account(WorldState::default()),
+            LedgerModel::Utxo => Ledger::Utxo(UtxoSet::default()),
+        };
+        for (height, block) in chain.iter().enumerate() {
+            ledger
+                .connect_block(block)
+                .map_err(|reason| ValidationError { height, reason })?;
+        }
+        Ok(ledger)
+    }
+
+    pub fn model(&self) -> LedgerModel {
+        match self {
+            Ledger::Account(_) => LedgerModel::Account,
+            Ledger::Utxo(_) => LedgerModel::Utxo,
+        }
+    }
+
+    /// Apply every transaction of a block, or none of them if one is invalid.
+    /// Returns the fees paid in the block.
+    pub fn connect_block(&mut self, block: &Block) -> Result<i64, InvalidBlock> {
+        match self {
+            Ledger::Account(state) => state.apply_block(block),
+            Ledger::Utxo(utxos) => utxos.connect_block(block),
+        }
+    }
+
+    /// Undo the last connected block.
+    pub fn disconnect_block(&mut self, block: &Block) {
+        match self {
+            Ledger::Account(state) => state.revert_block(block),
+            Ledger::Utxo(utxos) => utxos.disconnect_block(block),
+        };
+    }
+
+    /// Drop what is kept to undo the blocks up to `height`, which are final.
+    pub fn prune(&mut self, height: usize) {
+        match self {
+            // 口座の変更は取引そのものから戻せるので、何も持っていない
+            Ledger::Account(_) => {}
+            Ledger::Utxo(utxos) => utxos.prune(height),
+        };
+    }
+
+    /// Start applying transactions on top of this ledger without changing it,
+    /// to find those which can go into the next block.
+    pub fn draft(&self) -> Draft<'_> {
+        match self {
+            Ledger::Account(state) => Draft::Account(state.draft()),
+            Ledger::Utxo(utxos) => Draft::Utxo(utxos.draft()),
+        }
+    }
+
+    /// Nonce the next transaction sent from the address must have.
+    /// Only accounts have nonces, since UTXO transactions cannot be replayed.
+    pub fn get_next_nonce(&self, address: &str) -> u64 {
+        match self {
+            Ledger::Account(state) => state.get_next_nonce(address),
+            Ledger::Utxo(_) => 0,
+        }
+    }
+
+    /// Coins owned by the address.
+    pub fn get_balance(&self, address: &str) -> i64 {
+        match self {
+            Ledger::Account(state) => state.get_balance(address),
+            Ledger::Utxo(utxos) => utxos.get_balance(address),
+        }
+    }
+}
+
+/// Transactions applied on top of a ledger, which is left as it is.
+pub enum Draft<'a> {
+    Account(world_state::Draft<'a>),
+    Utxo(utxo_set::Draft<'a>),
+}
+
+impl Draft<'_> {
+    /// Apply a single transaction if it is valid after those applied before.
+    /// Returns the fee it pays.
+    pub fn apply_transaction(&mut self, txn: &str) -> Option<i64> {
+        match self {
+            Draft::Account(state) => serde_json::from_str::<Transaction>(txn)
+                .ok()
+                .and_then(|x| state.apply_transaction(&x)),
+            Draft::Utxo(utxos) => serde_json::from_str::<UtxoTransaction>(txn)
+                .ok()
+                .and_then(|x| utxos.apply_transaction(&x)),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+/// Upper bound in bytes of a block serialized as JSON.
+pub const MAX_BLOCK_SIZE: usize = 100_000;
+/// Upper bound of the number of transactions in a block.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 500;
+
+/// Count how many transactions from the front fit in a block whose header and
+/// coinbase transaction take `base_size` bytes.
+pub fn count_fitting_transactions(transactions: &[String], base_size: usize) -> usize {
+    let mut size = base_size;
+    let mut count = 0;
+    // 先頭のcoinbaseの分だけ、上限まで入れられる数は減る
+    for txn in transactions.iter().take(MAX_BLOCK_TRANSACTIONS - 1) {
+        // JSON文字列としてエスケープされた長さと、区切りのカンマの分だけ増える
+        let txn_size = serde_json::to_string(txn).unwrap().len() + 1;
+        if size + txn_size > MAX_BLOCK_SIZE {
+            break;
+        }
+        size += txn_size;
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transactions_beyond_the_count_limit_do_not_fit() {
+        let transactions = vec!["x".to_string(); MAX_BLOCK_TRANSACTIONS + 10];
+        // coinbaseの分を除いた数まで入る
+        assert_eq!(
+            count_fitting_transactions(&transactions, 0),
+            MAX_BLOCK_TRANSACTIONS - 1
+        );
+    }
+
+    #[test]
+    fn transactions_beyond_the_size_limit_do_not_fit() {
+        // 引用符2つとカンマ1つを足して、ちょうど10000バイトになる
+        let transactions = vec!["x".repeat(9_997); 20];
+        assert_eq!(count_fitting_transactions(&transactions, 0), 10);
+        assert_eq!(count_fitting_transactions(&transactions, 1), 9);
+    }
+}
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::block::BlockHeader;
+use crate::crypt::sha::get_double_sha256;
+
+/// Merkle root of an empty transaction list.
+const EMPTY_ROOT: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// Prefixes keeping the hash of a leaf apart from the hash of an inner node,
+/// so that a pair of child hashes cannot be passed off as a transaction.
+const LEAF_TAG: &str = "leaf:";
+const NODE_TAG: &str = "node:";
+
+/// Hash a transaction to get a leaf of the Merkle tree.
+pub fn hash_leaf(transaction: &str) -> String {
+    get_double_sha256(format!("{}{}", LEAF_TAG, transaction))
+}
+
+/// Hash two child nodes to get their parent node.
+fn hash_node(left: &str, right: &str) -> String {
+    get_double_sha256(format!("{}{}{}", NODE_TAG, left, right))
+}
+
+/// Build every level of the Merkle tree, from the leaves up to the root.
+pub fn build_tree(transactions: &[String]) -> Vec<Vec<String>> {
+    let mut levels = vec![transactions
+        .iter()
+        .map(|x| hash_leaf(x))
+        .collect::<Vec<String>>()];
+    while levels.last().unwrap().len() > 1 {
+        // 要素数が奇数の段では、最後の要素をそのまま上の段に上げる。
+        // Bitcoinのように複製すると、末尾を重複させた別の一覧が同じrootになってしまう (CVE-2012-2459)
+        let parents = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => hash_node(left, right),
+                _ => pair[0].clone(),
+            })
+            .collect();
+        levels.push(parents);
+    }
+    levels
+}
+
+/// Compute the Merkle root of the given transactions.
+pub fn compute_root(transactions: &[String]) -> String {
+    match build_tree(transactions).last().unwrap().first() {
+        Some(root) => root.clone(),
+        None => EMPTY_ROOT.to_string(),
+    }
+}
+
+/// Sibling hashes needed to recompute the Merkle root from a single leaf.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MerkleProof {
+    pub leaf_index: usize,
+    /// Number of transactions in the block, which tells on which levels the node has no sibling.
+    pub leaf_count: usize,
+    pub siblings: Vec<String>,
+}
+
+/// Proof that a transaction is included in the block with the given header.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct InclusionProof {
+    pub block_hash: String,
+    pub header: BlockHeader,
+    pub proof: MerkleProof,
+}
+
+/// Build an inclusion proof for the transaction at `index`.
+pub fn build_proof(transactions: &[String], index: usize) -> Option<MerkleProof> {
+    if index >= transactions.len() {
+        return None;
+    }
+    let levels = build_tree(transactions);
+    let mut siblings = Vec::new();
+    let mut i = index;
+    for level in &levels[..levels.len() - 1] {
+        siblings.extend(level.get(i ^ 1).cloned());
+        i /= 2;
+    }
+    Some(MerkleProof {
+        leaf_index: index,
+        leaf_count: transactions.len(),
+        siblings,
+    })
+}
+
+/// Check that `transaction` is committed to by `merkle_root` using only the proof.
+pub fn verify_proof(transaction: &str, proof: &MerkleProof, merkle_root: &str) -> bool {
+    if proof.leaf_index >= proof.leaf_count {
+        return false;
+    }
+    let mut hash = hash_leaf(transaction);
+    let mut i = proof.leaf_index;
+    let mut width = proof.leaf_count;
+    let mut siblings = proof.siblings.iter();
+    while width > 1 {
+        // 兄弟のない末尾のノードは、そのまま上の段に上がる
+        if i ^ 1 < width {
+            let Some(sibling) = siblings.next() else {
+                return false;
+            };
+            hash = if i.is_multiple_of(2) {
+                hash_node(&hash, sibling)
+            } else {
+                hash_node(sibling, &hash)
+            };
+        }
+        i /= 2;
+        width = width.div_ceil(2);
+    }
+    siblings.next().is_none() && hash == merkle_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transactions(count: usize) -> Vec<String> {
+        (0..count).map(|x| format!("transaction {}", x)).collect()
+    }
+
+    #[test]
+    fn root_of_a_single_transaction_is_its_leaf() {
+        assert_eq!(compute_root(&[]), EMPTY_ROOT);
+        let txs = transactions(1);
+        assert_eq!(compute_root(&txs), hash_leaf(&txs[0]));
+    }
+
+    #[test]
+    fn every_transaction_has_a_valid_proof() {
+        for count in 1..=9 {
+            let txs = transactions(count);
+            let root = compute_root(&txs);
+            for (i, txn) in txs.iter().enumerate() {
+                let proof = build_proof(&txs, i).unwrap();
+                assert!(verify_proof(txn, &proof, &root), "{} of {}", i, count);
+                assert!(!verify_proof("forged", &proof, &root));
+            }
+            assert!(build_proof(&txs, count).is_none());
+        }
+    }
+
+    #[test]
+    fn proof_with_another_position_is_rejected() {
+        let txs = transactions(5);
+        let root = compute_root(&txs);
+        let mut proof = build_proof(&txs, 4).unwrap();
+        proof.leaf_index = 5;
+        assert!(!verify_proof(&txs[4], &proof, &root));
+        let mut proof = build_proof(&txs, 1).unwrap();
+        proof.leaf_index = 0;
+        assert!(!verify_proof(&txs[1], &proof, &root));
+    }
+
+    #[test]
+    fn duplicated_last_transaction_changes_the_root() {
+        let txs = transactions(3);
+        let mut duplicated = txs.clone();
+        duplicated.push(txs[2].clone());
+        assert_ne!(compute_root(&txs), compute_root(&duplicated));
+    }
+
+    #[test]
+    fn inner_node_cannot_pass_as_a_transaction() {
+        let txs = transactions(2);
+        let leaves: Vec<String> = txs.iter().map(|x| hash_leaf(x)).collect();
+        let forged = format!("{}{}", leaves[0], leaves[1]);
+        assert_ne!(hash_leaf(&forged), compute_root(&txs));
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::crypt::sha::get_double_sha256;
+use crate::Block;
+
+/// Number of nonces a worker tries between checks of the abort flags.
+const BATCH_SIZE: u128 = 1000;
+const HASHRATE_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Proof of Work miner splitting the nonce space across worker threads.
+#[derive(Clone)]
+pub struct Miner {
+    num_threads: usize,
+    /// 競合するブロックを受信したときなど、現在の採掘だけを中断する
+    aborted: Arc<AtomicBool>,
+    /// シャットダウン時に立て、以降の採掘をすべて止める
+    stopped: Arc<AtomicBool>,
+}
+
+impl Miner {
+    pub fn new() -> Miner {
+        let num_threads = thread::available_parallelism()
+            .map(|x| x.get())
+            .unwrap_or(1);
+        println!("Initializing Miner with {} threads ...", num_threads);
+        Miner {
+            num_threads,
+            aborted: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Forget an earlier abort, when a new block is prepared.
+    /// An abort after this point aborts the mining of that block, even if it has not started.
+    pub fn clear_abort(&self) {
+        self.aborted.store(false, Ordering::SeqCst);
+    }
+
+    /// Abort the block currently being mined, or the one prepared for it.
+    pub fn abort(&self) {
+        println!("Aborting the current mining ...");
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop mining for good.
+    pub fn stop(&self) {
+        println!("Stopping the miner ...");
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst) || self.is_stopped()
+    }
+
+    /// Find a nonce for the block and seal it.
+    /// Return `None` if mining was aborted or stopped before a nonce was found.
+    pub fn mine(&self, block: &mut Block) -> Option<u128> {
+        // difficultyの数字を増やせば増やすほど、末尾で揃えなければならない桁数が増える
+        let suffix = "0".repeat(block.header.difficulty);
+        let msg = block.header.to_unsealed_string();
+        let found: Mutex<Option<u128>> = Mutex::new(None);
+        let done = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let started = Instant::now();
+
+        thread::scope(|s| {
+            // 各スレッドは start, start + num_threads, ... の nonce を総当たりで試す
+            for start in 0..self.num_threads {
+                let (suffix, msg, found, done, hashes) = (&suffix, &msg, &found, &done, &hashes);
+                let step = self.num_threads as u128;
+                s.spawn(move || {
+                    let mut nonce = start as u128;
+                    while !done.load(Ordering::SeqCst) && !self.is_interrupted() {
+                        for _ in 0..BATCH_SIZE {
+                            let digest = get_double_sha256(format!("{}{}", msg, nonce));
+                            if digest.ends_with(suffix) {
+                                *found.lock().unwrap() = Some(nonce);
+                                done.store(true, Ordering::SeqCst);
+                                break;
+                            };
+                            nonce += step;
+                        }
+                        hashes.fetch_add(BATCH_SIZE as u64, Ordering::SeqCst);
+                    }
+                });
+            }
+
+            s.spawn(|| {
+                let mut last_report = Instant::now();
+                while !done.load(Ordering::SeqCst) && !self.is_interrupted() {
+                    thread::sleep(Duration::from_millis(100));
+                    if last_report.elapsed() >= HASHRATE_REPORT_INTERVAL {
+                        println!("Mining ... {}", hashrate(&hashes, started));
+                        last_report = Instant::now();
+                    }
+                }
+            });
+        });
+
+        let nonce = *found.lock().unwrap();
+        match nonce {
+            Some(nonce) => {
+                println!("Found a nonce: {} ({})", nonce, hashrate(&hashes, started));
+                block.header.nonce = Some(nonce);
+            }
+            None => println!("Mining was interrupted ..."),
+        };
+        nonce
+    }
+}
+
+fn hashrate(hashes: &AtomicU64, started: Instant) -> String {
+    let secs = started.elapsed().as_secs_f64().max(f64::EPSILON);
+    format!("{:.0} H/s", hashes.load(Ordering::SeqCst) as f64 / secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abort_before_mining_starts_is_not_lost() {
+        let miner = Miner::new();
+        let mut block = Block::new(Vec::new(), None, 1, 0);
+        miner.clear_abort();
+        miner.abort();
+        assert_eq!(miner.mine(&mut block), None);
+
+        miner.clear_abort();
+        assert!(miner.mine(&mut block).is_some());
+    }
+}
@@ -1,2 +1,16 @@
+pub mod ancestors;
 pub mod block;
 pub mod chain;
+pub mod difficulty;
+pub mod export;
+pub mod ledger;
+pub mod limits;
+pub mod merkle;
+pub mod miner;
+pub mod reward;
+pub mod spec;
+pub mod timestamp;
+pub mod tree;
+pub mod utxo_set;
+pub mod validation;
+pub mod world_state;
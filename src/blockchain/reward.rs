@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+/// Monetary policy of a chain, given in the chain spec.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewardSpec {
+    /// Coins issued to the miner of each block until the first halving.
+    pub initial_subsidy: i32,
+    /// The subsidy is halved every this number of blocks.
+    pub halving_interval: usize,
+    /// Mining issues no more coins once this many have been, apart from the genesis allocations.
+    pub max_supply: i64,
+}
+
+impl Default for RewardSpec {
+    fn default() -> Self {
+        RewardSpec {
+            initial_subsidy: 50,
+            halving_interval: 210,
+            max_supply: 21_000,
+        }
+    }
+}
+
+/// Number of coins the coinbase transaction of the block at `height` issues.
+pub fn block_subsidy(spec: &RewardSpec, height: usize) -> i32 {
+    if height == 0 {
+        return 0;
+    }
+    (issued_supply(spec, height) - issued_supply(spec, height - 1)) as i32
+}
+
+/// Total number of coins issued by the blocks up to and including `height`.
+pub fn issued_supply(spec: &RewardSpec, height: usize) -> i64 {
+    let mut supply = 0;
+    // genesis blockにcoinbaseはないので、高さ1から数える
+    let mut start = 1;
+    while start <= height {
+        let halvings = start / spec.halving_interval;
+        if halvings >= i32::BITS as usize {
+            break;
+        }
+        let end = ((halvings + 1) * spec.halving_interval - 1).min(height);
+        supply += i64::from(spec.initial_subsidy >> halvings) * (end - start + 1) as i64;
+        start = end + 1;
+    }
+    supply.min(spec.max_supply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsidy_halves_every_interval() {
+        let spec = RewardSpec::default();
+        assert_eq!(block_subsidy(&spec, 0), 0);
+        assert_eq!(block_subsidy(&spec, 1), 50);
+        assert_eq!(block_subsidy(&spec, 209), 50);
+        assert_eq!(block_subsidy(&spec, 210), 25);
+        assert_eq!(block_subsidy(&spec, 420), 12);
+        assert_eq!(issued_supply(&spec, 209), 50 * 209);
+        assert_eq!(issued_supply(&spec, 211), 50 * 209 + 25 * 2);
+    }
+
+    #[test]
+    fn supply_never_exceeds_the_maximum() {
+        let spec = RewardSpec {
+            initial_subsidy: 10,
+            halving_interval: 100,
+            max_supply: 995,
+        };
+        assert_eq!(issued_supply(&spec, 99), 990);
+        // 上限をまたぐブロックは端数だけ発行する
+        assert_eq!(block_subsidy(&spec, 100), 5);
+        assert_eq!(block_subsidy(&spec, 101), 0);
+        assert_eq!(issued_supply(&spec, 10_000), 995);
+        assert_eq!(issued_supply(&spec, usize::MAX / 2), 995);
+    }
+}
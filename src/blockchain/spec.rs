@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::difficulty::MIN_DIFFICULTY;
+use crate::blockchain::ledger::LedgerModel;
+use crate::blockchain::reward::RewardSpec;
+use crate::consensus::finality::FinalitySpec;
+use crate::consensus::ConsensusSpec;
+use crate::{Block, ToVecString, Transaction};
+
+/// Chain spec used when none is given on the command line.
+const DEFAULT_SPEC: &str = include_str!("../../chainspec/dev.json");
+
+/// Definition of a network which every node joining it must share.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChainSpec {
+    /// Name of the network, which also separates its data directory from other networks.
+    pub name: String,
+    /// Port core nodes of the network listen on by default.
+    pub port: u16,
+    /// Consensus engine of the network. Proof of Work if not given.
+    #[serde(default)]
+    pub consensus: ConsensusSpec,
+    /// Ledger model of the network. Account balances if not given.
+    #[serde(default)]
+    pub ledger: LedgerModel,
+    /// Validators of the BFT finality layer. Blocks are never final if not given.
+    #[serde(default)]
+    pub finality: Option<FinalitySpec>,
+    /// Subsidy paid to miners and its limits. The default policy if not given.
+    #[serde(default)]
+    pub reward: RewardSpec,
+    pub genesis: GenesisSpec,
+}
+
+/// Fields of the genesis block, fixed so that every node builds the same one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GenesisSpec {
+    pub timestamp: i64,
+    /// Difficulty of the genesis block, which the following blocks start from.
+    pub difficulty: usize,
+    /// The genesis block is not mined, so its nonce is given as it is.
+    #[serde(default)]
+    pub nonce: u128,
+    /// Coins issued to addresses at the start of the chain.
+    #[serde(default)]
+    pub allocations: Vec<Allocation>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Allocation {
+    pub address: String,
+    pub value: i32,
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        serde_json::from_str(DEFAULT_SPEC).expect("the default chain spec is malformed")
+    }
+}
+
+impl ChainSpec {
+    /// Read a chain spec from a JSON file.
+    pub fn load(path: &Path) -> anyhow::Result<ChainSpec> {
+        let content = fs::read(path)
+            .with_context(|| format!("failed to read chain spec {}", path.display()))?;
+        let spec: ChainSpec = serde_json::from_slice(&content)
+            .with_context(|| format!("chain spec {} is malformed", path.display()))?;
+        // difficultyが0だと、どんなnonceでも採掘できてしまう
+        if matches!(spec.consensus, ConsensusSpec::ProofOfWork)
+            && spec.genesis.difficulty < MIN_DIFFICULTY
+        {
+            return Err(anyhow!(
+                "genesis difficulty of chain spec {} is below the minimum of {}",
+                path.display(),
+                MIN_DIFFICULTY
+            ));
+        }
+        if spec.reward.halving_interval == 0
+            || spec.reward.initial_subsidy < 0
+            || spec.reward.max_supply < 0
+        {
+            return Err(anyhow!(
+                "reward of chain spec {} needs a positive halving interval and no negative coins",
+                path.display()
+            ));
+        }
+        Ok(spec)
+    }
+
+    /// Read the chain spec at `path`, or use the default one if not given.
+    pub fn load_or_default(path: Option<&Path>) -> anyhow::Result<ChainSpec> {
+        match path {
+            Some(path) => ChainSpec::load(path),
+            None => Ok(ChainSpec::default()),
+        }
+    }
+
+    /// Directory under `base` in which the blockchain of this network is kept.
+    pub fn data_dir(&self, base: &Path) -> PathBuf {
+        base.join(&self.name)
+    }
+
+    /// Identifier carried by every message of this network.
+    pub fn network_id(&self) -> String {
+        self.genesis_block().header.hash()
+    }
+
+    /// Build the genesis block, which depends on nothing but this spec.
+    pub fn genesis_block(&self) -> Block {
+        // 初期配布はcoinbaseトランザクションとしてgenesis blockに記録する
+        let allocations: Vec<Transaction> = self
+            .genesis
+            .allocations
+            .iter()
+            .map(|x| Transaction::new_coinbase(&x.address, x.value))
+            .collect();
+        let mut block = Block::new(
+            allocations.to_vec_string(),
+            None,
+            self.genesis.difficulty,
+            self.genesis.timestamp,
+        );
+        block.header.nonce = Some(self.genesis.nonce);
+        block
+    }
+
+    /// Total number of coins allocated by the genesis block.
+    pub fn allocated_supply(&self) -> i64 {
+        self.genesis
+            .allocations
+            .iter()
+            .map(|x| i64::from(x.value))
+            .sum()
+    }
+}
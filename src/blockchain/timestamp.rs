@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::blockchain::ancestors::Ancestors;
+use crate::core::clock::Clock;
+
+/// A block must be newer than the median timestamp of this number of preceding blocks.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far in seconds a block timestamp may be ahead of the network-adjusted time.
+pub const MAX_FUTURE_DRIFT: i64 = 2 * 60 * 60;
+/// Peers must agree on our clock being off by at most this many seconds to adjust it.
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+/// Number of peers needed before their clocks are taken into account.
+const MIN_TIME_SAMPLES: usize = 5;
+/// Samples beyond this number are not recorded.
+const MAX_TIME_SAMPLES: usize = 200;
+
+/// Median timestamp of the last blocks of `chain`, which the next block must exceed.
+pub fn median_time_past(chain: &Ancestors) -> i64 {
+    let recent = chain.recent();
+    let start = recent.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<i64> = recent[start..].iter().map(|x| x.header.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps
+        .get(timestamps.len() / 2)
+        .copied()
+        .unwrap_or(i64::MIN)
+}
+
+/// Our clock corrected by the median offset of the clocks of our peers.
+#[derive(Clone)]
+pub struct NetworkTime {
+    clock: Arc<dyn Clock>,
+    /// Offset of the clock of each peer host from ours, taken from its first message.
+    offsets: Arc<Mutex<HashMap<IpAddr, i64>>>,
+}
+
+impl NetworkTime {
+    pub fn new(clock: Arc<dyn Clock>) -> NetworkTime {
+        NetworkTime {
+            clock,
+            offsets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Record the time a peer reported in its message, unless the host it connected from
+    /// was sampled already, so that a peer cannot outvote others by sending many messages.
+    pub fn add_sample(&self, peer: IpAddr, peer_time: i64) {
+        let mut offsets = self.offsets.lock().unwrap();
+        if offsets.len() >= MAX_TIME_SAMPLES {
+            return;
+        }
+        let offset = peer_time - self.clock.now();
+        offsets.entry(peer).or_insert(offset);
+    }
+
+    /// Median offset of the peer clocks from ours.
+    pub fn get_offset(&self) -> i64 {
+        let mut offsets: Vec<i64> = self.offsets.lock().unwrap().values().copied().collect();
+        if offsets.len() < MIN_TIME_SAMPLES {
+            return 0;
+        }
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+        // 大きくずれている場合は、自分の時計より多数のピアを信用する理由がないので補正しない
+        if median.abs() > MAX_TIME_ADJUSTMENT {
+            println!("peer clocks are too far from ours to adjust: {}s", median);
+            0
+        } else {
+            median
+        }
+    }
+
+    /// Current network-adjusted time as a UNIX timestamp.
+    pub fn now(&self) -> i64 {
+        self.clock.now() + self.get_offset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::MockClock;
+    use std::net::Ipv4Addr;
+
+    fn host(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))
+    }
+
+    #[test]
+    fn offset_is_the_median_of_enough_peers() {
+        let time = NetworkTime::new(Arc::new(MockClock::new(1000)));
+        for i in 0..MIN_TIME_SAMPLES as u8 - 1 {
+            time.add_sample(host(i), 1000 + 60);
+        }
+        assert_eq!(time.now(), 1000);
+        time.add_sample(host(100), 1000 - 30);
+        assert_eq!(time.now(), 1000 + 60);
+    }
+
+    #[test]
+    fn a_peer_is_sampled_only_once() {
+        let time = NetworkTime::new(Arc::new(MockClock::new(1000)));
+        for i in 0..MIN_TIME_SAMPLES as u8 {
+            time.add_sample(host(i), 1000);
+        }
+        // 同じホストから何度送っても、中央値は動かせない
+        for _ in 0..MIN_TIME_SAMPLES {
+            time.add_sample(host(0), 1000 + 600);
+            time.add_sample(host(1), 1000 + 600);
+            time.add_sample(host(2), 1000 + 600);
+        }
+        assert_eq!(time.get_offset(), 0);
+    }
+}
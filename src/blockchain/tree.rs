@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use crate::blockchain::ancestors::{Ancestors, LOOKBACK};
+use crate::consensus::{ChainScore, EngineState};
+use crate::Block;
+
+/// Block stored in the tree together with its position and the engine state after it.
+#[derive(Clone)]
+struct TreeEntry {
+    block: Block,
+    score: ChainScore,
+    state: EngineState,
+}
+
+/// Every known valid block, including those on branches other than the active one.
+#[derive(Clone)]
+pub struct BlockTree {
+    entries: HashMap<String, TreeEntry>,
+    tips: HashSet<String>,
+}
+
+impl BlockTree {
+    pub fn new(genesis_block: Block, weight: u128, state: EngineState) -> BlockTree {
+        let hash = genesis_block.header.hash();
+        let entry = TreeEntry {
+            block: genesis_block,
+            score: ChainScore {
+                height: 0,
+                total_weight: weight,
+            },
+            state,
+        };
+        BlockTree {
+            entries: HashMap::from([(hash.clone(), entry)]),
+            tips: HashSet::from([hash]),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Block> {
+        self.entries.get(hash).map(|x| &x.block)
+    }
+
+    /// Height of the block with the given hash, on whichever branch it is.
+    pub fn height_of(&self, hash: &str) -> Option<usize> {
+        self.entries.get(hash).map(|x| x.score.height)
+    }
+
+    /// Add a block whose parent is already in the tree and return its hash.
+    /// `weight` is what the block adds to the score of its branch,
+    /// and `state` is the state of the consensus engine after it.
+    pub fn insert(&mut self, block: Block, weight: u128, state: EngineState) -> String {
+        let hash = block.header.hash();
+        let parent_hash = block.header.previous_block_hash.clone().unwrap();
+        let parent = &self.entries[&parent_hash].score;
+        let entry = TreeEntry {
+            score: ChainScore {
+                height: parent.height + 1,
+                total_weight: parent.total_weight + weight,
+            },
+            block,
+            state,
+        };
+        self.tips.remove(&parent_hash);
+        self.tips.insert(hash.clone());
+        self.entries.insert(hash.clone(), entry);
+        hash
+    }
+
+    /// Ancestors of the block following the one with the given hash.
+    /// Only as many blocks as the rules look back on are copied.
+    pub fn ancestors(&self, hash: &str) -> Ancestors<'static> {
+        let mut recent = Vec::new();
+        let mut current = self.entries.get(hash);
+        let len = current.map_or(0, |x| x.score.height + 1);
+        let state = current.and_then(|x| x.state.clone());
+        while let Some(entry) = current {
+            if recent.len() == LOOKBACK {
+                break;
+            }
+            recent.push(entry.block.clone());
+            current = entry
+                .block
+                .header
+                .previous_block_hash
+                .as_ref()
+                .and_then(|x| self.entries.get(x));
+        }
+        recent.reverse();
+        Ancestors::new(len, recent, state)
+    }
+
+    /// Blocks passed on the way from the block `from` to the block `to` through their
+    /// fork point: those of `from`'s branch after it, newest first, and those of `to`'s
+    /// branch after it, oldest first. Both blocks must be in the tree.
+    pub fn route(&self, from: &str, to: &str) -> (Vec<&Block>, Vec<&Block>) {
+        let mut from = &self.entries[from];
+        let mut to = &self.entries[to];
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        // 高い方から親へたどり、同じブロックに着いたところが分岐点
+        while !std::ptr::eq(from, to) {
+            if from.score.height >= to.score.height {
+                disconnected.push(&from.block);
+                from = self.parent(from);
+            } else {
+                connected.push(&to.block);
+                to = self.parent(to);
+            }
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
+    fn parent(&self, entry: &TreeEntry) -> &TreeEntry {
+        let hash = entry.block.header.previous_block_hash.as_ref().unwrap();
+        &self.entries[hash]
+    }
+
+    /// Check whether the block with hash `hash` is `ancestor` or one of its descendants.
+    pub fn descends_from(&self, hash: &str, ancestor: &str) -> bool {
+        let ancestor_height = match self.entries.get(ancestor) {
+            Some(entry) => entry.score.height,
+            None => return false,
+        };
+        // ジェネシスブロックからは全てのブロックが続いている
+        if ancestor_height == 0 {
+            return true;
+        }
+        let mut current = self.entries.get(hash);
+        while let Some(entry) = current {
+            if entry.score.height <= ancestor_height {
+                return entry.block.header.hash() == ancestor;
+            }
+            current = entry
+                .block
+                .header
+                .previous_block_hash
+                .as_ref()
+                .and_then(|x| self.entries.get(x));
+        }
+        false
+    }
+
+    /// Return the tip of the best branch according to `compare`
+    /// among the branches containing the finalized block.
+    /// On a tie, `current_tip` wins so that a node sticks to the branch it saw first.
+    pub fn best_tip(
+        &self,
+        current_tip: &str,
+        finalized: &str,
+        compare: impl Fn(&ChainScore, &ChainScore) -> Ordering,
+    ) -> String {
+        let mut best = current_tip;
+        for tip in &self.tips {
+            if self.descends_from(tip, finalized)
+                && compare(&self.entries[tip.as_str()].score, &self.entries[best].score)
+                    == Ordering::Greater
+            {
+                best = tip;
+            }
+        }
+        best.to_string()
+    }
+}
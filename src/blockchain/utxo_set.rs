@@ -0,0 +1,373 @@
+use anyhow::anyhow;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::blockchain::ledger;
+use crate::blockchain::validation::InvalidBlock;
+use crate::transaction::utxo::{OutPoint, TxOutput, UtxoTransaction};
+use crate::{Block, Transaction};
+
+/// Unspent outputs of a chain, updated as blocks are connected and disconnected.
+#[derive(Debug, Default)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, TxOutput>,
+    /// Outputs spent by each connected block above the pruned ones, oldest first,
+    /// restored when the block is disconnected.
+    spent: VecDeque<Vec<(OutPoint, TxOutput)>>,
+    /// Number of blocks from the genesis block whose spent outputs were dropped.
+    pruned: usize,
+}
+
+/// Transaction of a block as the UTXO ledger sees it.
+enum Entry {
+    Coinbase(Transaction),
+    Vote,
+    Spend(UtxoTransaction),
+}
+
+/// Decide what the transaction at position `i` of a block is. Only a coinbase position
+/// may issue coins, and nowhere else is a coinbase transaction accepted.
+fn parse_entry(block: &Block, i: usize) -> Option<Entry> {
+    let txn = &block.transactions()[i];
+    if ledger::is_coinbase_position(block, i) {
+        return serde_json::from_str::<Transaction>(txn)
+            .ok()
+            .filter(|x| x.is_coinbase())
+            .map(Entry::Coinbase);
+    }
+    match serde_json::from_str::<Transaction>(txn) {
+        Ok(txn) if txn.get_vote().is_some() => Some(Entry::Vote),
+        // 口座間の送金やcoinbaseはUTXO台帳では使えない
+        Ok(_) => None,
+        Err(_) => serde_json::from_str(txn).ok().map(Entry::Spend),
+    }
+}
+
+impl UtxoSet {
+    /// Spend the inputs and create the outputs of every transaction of a block,
+    /// or change nothing if one of them spends an unknown or already spent output.
+    /// Returns the fees paid in the block.
+    pub fn connect_block(&mut self, block: &Block) -> Result<i64, InvalidBlock> {
+        let block_hash = block.header.hash();
+        let mut created = HashMap::new();
+        let mut spent = HashMap::new();
+        let mut fees = 0;
+        for i in 0..block.transactions().len() {
+            let txn = match parse_entry(block, i) {
+                Some(Entry::Coinbase(txn)) => {
+                    let out_point = OutPoint {
+                        txid: block_hash.clone(),
+                        index: i as u32,
+                    };
+                    let output = TxOutput {
+                        owner: txn.get_recipient().to_string(),
+                        value: txn.get_value(),
+                    };
+                    created.insert(out_point, output);
+                    continue;
+                }
+                Some(Entry::Vote) => continue,
+                Some(Entry::Spend(txn)) => txn,
+                None => return Err(InvalidBlock::MalformedTransaction(i)),
+            };
+            if !txn.has_valid_signature() {
+                return Err(InvalidBlock::InvalidTransactionSignature(i));
+            }
+            let signer = txn.get_signer_address();
+            let mut input_value = 0;
+            for input in txn.get_inputs() {
+                // 同じブロックの前の方で作られた出力も使える
+                let output = match created.remove(input) {
+                    Some(output) => output,
+                    None if spent.contains_key(input) => return Err(InvalidBlock::DoubleSpend(i)),
+                    None => {
+                        let output = self
+                            .outputs
+                            .get(input)
+                            .cloned()
+                            .ok_or(InvalidBlock::DoubleSpend(i))?;
+                        spent.insert(input.clone(), output.clone());
+                        output
+                    }
+                };
+                if output.owner != signer {
+                    return Err(InvalidBlock::InvalidTransactionSignature(i));
+                }
+                input_value += i64::from(output.value);
+            }
+            if txn.output_value() != input_value {
+                return Err(InvalidBlock::UnbalancedTransaction(i));
+            }
+            fees += i64::from(txn.get_fee());
+            let txid = txn.txid();
+            for (index, output) in txn.get_outputs().iter().enumerate() {
+                let out_point = OutPoint {
+                    txid: txid.clone(),
+                    index: index as u32,
+                };
+                created.insert(out_point, output.clone());
+            }
+        }
+
+        for out_point in spent.keys() {
+            self.outputs.remove(out_point);
+        }
+        self.outputs.extend(created);
+        self.spent.push_back(spent.into_iter().collect());
+        Ok(fees)
+    }
+
+    /// Undo `connect_block` for the last connected block.
+    pub fn disconnect_block(&mut self, block: &Block) {
+        let block_hash = block.header.hash();
+        for i in 0..block.transactions().len() {
+            match parse_entry(block, i) {
+                Some(Entry::Coinbase(_)) => {
+                    self.outputs.remove(&OutPoint {
+                        txid: block_hash.clone(),
+                        index: i as u32,
+                    });
+                }
+                Some(Entry::Spend(txn)) => {
+                    let txid = txn.txid();
+                    for index in 0..txn.get_outputs().len() {
+                        self.outputs.remove(&OutPoint {
+                            txid: txid.clone(),
+                            index: index as u32,
+                        });
+                    }
+                }
+                _ => {}
+            };
+        }
+        let spent = self
+            .spent
+            .pop_back()
+            .expect("pruned block cannot be disconnected");
+        self.outputs.extend(spent);
+    }
+
+    /// Drop the spent outputs kept for the blocks up to `height`, which are final
+    /// and never disconnected.
+    pub fn prune(&mut self, height: usize) {
+        while self.pruned <= height && self.spent.pop_front().is_some() {
+            self.pruned += 1;
+        }
+    }
+
+    /// Check that every input of a transaction is unspent and owned by its signer,
+    /// and that they add up to its outputs and fee.
+    pub fn check_transaction(&self, txn: &UtxoTransaction) -> anyhow::Result<()> {
+        check_inputs(txn, |x| self.outputs.get(x))
+    }
+
+    /// Start applying transactions on top of this set without changing it.
+    pub fn draft(&self) -> Draft<'_> {
+        Draft {
+            utxos: self,
+            created: HashMap::new(),
+            spent: HashSet::new(),
+        }
+    }
+
+    /// Sum of the unspent outputs owned by the address.
+    pub fn get_balance(&self, owner: &str) -> i64 {
+        self.outputs
+            .values()
+            .filter(|x| x.owner == owner)
+            .map(|x| i64::from(x.value))
+            .sum()
+    }
+}
+
+/// Check the inputs of a transaction against the unspent outputs found by `get_output`.
+fn check_inputs<'a>(
+    txn: &UtxoTransaction,
+    get_output: impl Fn(&OutPoint) -> Option<&'a TxOutput>,
+) -> anyhow::Result<()> {
+    if !txn.has_valid_signature() {
+        return Err(anyhow!("signature is invalid"));
+    }
+    let signer = txn.get_signer_address();
+    let mut input_value = 0;
+    for input in txn.get_inputs() {
+        let output = get_output(input)
+            .ok_or_else(|| anyhow!("output {}:{} is unknown or spent", input.txid, input.index))?;
+        if output.owner != signer {
+            return Err(anyhow!(
+                "output {}:{} is not owned by the signer",
+                input.txid,
+                input.index
+            ));
+        }
+        input_value += i64::from(output.value);
+    }
+    if txn.output_value() != input_value {
+        return Err(anyhow!(
+            "outputs and fee of {} do not match the inputs of {}",
+            txn.output_value(),
+            input_value
+        ));
+    }
+    Ok(())
+}
+
+/// Transactions applied on top of a UTXO set, which is left as it is.
+pub struct Draft<'a> {
+    utxos: &'a UtxoSet,
+    /// Outputs created by the applied transactions and not spent yet.
+    created: HashMap<OutPoint, TxOutput>,
+    /// Outputs of the set spent by the applied transactions.
+    spent: HashSet<OutPoint>,
+}
+
+impl Draft<'_> {
+    /// Spend the inputs and create the outputs of a single transaction if it is valid.
+    /// Returns the fee it pays.
+    pub fn apply_transaction(&mut self, txn: &UtxoTransaction) -> Option<i64> {
+        check_inputs(txn, |x| self.get_output(x)).ok()?;
+        for input in txn.get_inputs() {
+            if self.created.remove(input).is_none() {
+                self.spent.insert(input.clone());
+            }
+        }
+        let txid = txn.txid();
+        for (index, output) in txn.get_outputs().iter().enumerate() {
+            let out_point = OutPoint {
+                txid: txid.clone(),
+                index: index as u32,
+            };
+            self.created.insert(out_point, output.clone());
+        }
+        Some(i64::from(txn.get_fee()))
+    }
+
+    fn get_output(&self, out_point: &OutPoint) -> Option<&TxOutput> {
+        match self.created.get(out_point) {
+            Some(output) => Some(output),
+            None if self.spent.contains(out_point) => None,
+            None => self.utxos.outputs.get(out_point),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::keys::KeyPair;
+
+    fn genesis() -> Block {
+        let allocation = Transaction::new_coinbase("alice", 1000);
+        Block::new(vec![allocation.to_string()], None, 1, 0)
+    }
+
+    #[test]
+    fn coinbase_outside_the_head_of_a_block_is_rejected() {
+        let genesis = genesis();
+        let mut utxos = UtxoSet::default();
+        utxos.connect_block(&genesis).unwrap();
+
+        // UTXOトランザクションにもcoinbaseにも読めるように、両方のフィールドを持たせる
+        let forged = r#"{"sender":"coinbase","recipient":"mallory","value":1000000,"fee":0,"nonce":0,"inputs":[{"txid":"00","index":0}],"outputs":[{"owner":"mallory","value":1}],"public_key":"00"}"#;
+        assert!(serde_json::from_str::<Transaction>(forged).is_err());
+        assert!(serde_json::from_str::<UtxoTransaction>(forged).is_err());
+
+        let coinbase = Transaction::new_coinbase("miner", 50);
+        let block = Block::new(
+            vec![coinbase.to_string(), forged.to_string()],
+            Some(genesis.header.hash()),
+            1,
+            1,
+        );
+        assert_eq!(
+            utxos.connect_block(&block),
+            Err(InvalidBlock::MalformedTransaction(1))
+        );
+        assert_eq!(utxos.get_balance("mallory"), 0);
+        assert_eq!(utxos.get_balance("miner"), 0);
+    }
+
+    #[test]
+    fn genesis_allocations_and_the_head_coinbase_issue_coins() {
+        let genesis = genesis();
+        let mut utxos = UtxoSet::default();
+        utxos.connect_block(&genesis).unwrap();
+        let coinbase = Transaction::new_coinbase("miner", 50);
+        let block = Block::new(
+            vec![coinbase.to_string()],
+            Some(genesis.header.hash()),
+            1,
+            1,
+        );
+        utxos.connect_block(&block).unwrap();
+        assert_eq!(utxos.get_balance("alice"), 1000);
+        assert_eq!(utxos.get_balance("miner"), 50);
+
+        utxos.disconnect_block(&block);
+        assert_eq!(utxos.get_balance("miner"), 0);
+    }
+
+    #[test]
+    fn spent_outputs_are_kept_only_above_the_pruned_height() {
+        let genesis = genesis();
+        let mut utxos = UtxoSet::default();
+        utxos.connect_block(&genesis).unwrap();
+        let mut parent = genesis.header.hash();
+        let mut blocks = Vec::new();
+        for height in 1..=3 {
+            let coinbase = Transaction::new_coinbase("miner", 50);
+            let block = Block::new(vec![coinbase.to_string()], Some(parent), 1, height);
+            utxos.connect_block(&block).unwrap();
+            parent = block.header.hash();
+            blocks.push(block);
+        }
+        assert_eq!(utxos.spent.len(), 4);
+
+        utxos.prune(2);
+        assert_eq!(utxos.spent.len(), 1);
+        utxos.disconnect_block(&blocks[2]);
+        assert_eq!(utxos.get_balance("miner"), 100);
+        // 既に刈り込んだ高さを渡しても何も起きない
+        utxos.prune(1);
+        assert!(utxos.spent.is_empty());
+    }
+
+    #[test]
+    fn draft_spends_its_own_outputs_and_leaves_the_set_unchanged() {
+        let alice = KeyPair::from_seed("alice");
+        let allocation = Transaction::new_coinbase(alice.address(), 1000);
+        let genesis = Block::new(vec![allocation.to_string()], None, 1, 0);
+        let mut utxos = UtxoSet::default();
+        utxos.connect_block(&genesis).unwrap();
+        let allocated = OutPoint {
+            txid: genesis.header.hash(),
+            index: 0,
+        };
+        let output = |owner: &str, value| TxOutput {
+            owner: owner.to_string(),
+            value,
+        };
+
+        let mut draft = utxos.draft();
+        let first = UtxoTransaction::new_signed(
+            &alice,
+            vec![allocated.clone()],
+            vec![output(&alice.address(), 900), output("bob", 90)],
+            10,
+        );
+        assert_eq!(draft.apply_transaction(&first), Some(10));
+        // 前の取引で作られた出力は使えるが、使われた出力は二度と使えない
+        let change = OutPoint {
+            txid: first.txid(),
+            index: 0,
+        };
+        let second =
+            UtxoTransaction::new_signed(&alice, vec![change], vec![output("carol", 899)], 1);
+        assert_eq!(draft.apply_transaction(&second), Some(1));
+        let again =
+            UtxoTransaction::new_signed(&alice, vec![allocated], vec![output("dave", 1000)], 0);
+        assert_eq!(draft.apply_transaction(&again), None);
+
+        assert_eq!(utxos.get_balance(&alice.address()), 1000);
+        assert_eq!(utxos.get_balance("carol"), 0);
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt;
+
+use crate::blockchain::ancestors::Ancestors;
+use crate::blockchain::ledger::Ledger;
+use crate::blockchain::limits::{MAX_BLOCK_SIZE, MAX_BLOCK_TRANSACTIONS};
+use crate::blockchain::merkle;
+use crate::blockchain::reward::{self, RewardSpec};
+use crate::blockchain::timestamp::{self, MAX_FUTURE_DRIFT};
+use crate::consensus::Consensus;
+use crate::{Block, Transaction};
+
+/// Reason why a block was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidBlock {
+    GenesisMismatch,
+    UnknownParent,
+    ConflictsWithFinalized,
+    PreviousHashMismatch,
+    TooManyTransactions(usize),
+    TooLarge(usize),
+    DifficultyMismatch { expected: usize, actual: usize },
+    InvalidProofOfWork,
+    TimestampTooOld,
+    TimestampTooFarInFuture,
+    UnexpectedSigner,
+    InvalidSignature,
+    MerkleRootMismatch,
+    MissingCoinbase,
+    CoinbaseValueMismatch { expected: i64, actual: i64 },
+    MalformedTransaction(usize),
+    Overdraft(usize),
+    NonceMismatch(usize),
+    DoubleSpend(usize),
+    UnbalancedTransaction(usize),
+    InvalidTransactionSignature(usize),
+}
+
+impl fmt::Display for InvalidBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidBlock::GenesisMismatch => write!(f, "genesis block does not match ours"),
+            InvalidBlock::UnknownParent => write!(f, "parent block is unknown"),
+            InvalidBlock::ConflictsWithFinalized => {
+                write!(
+                    f,
+                    "parent block is not on the branch of the finalized block"
+                )
+            }
+            InvalidBlock::PreviousHashMismatch => {
+                write!(f, "previous block hash does not match the parent block")
+            }
+            InvalidBlock::TooManyTransactions(count) => write!(
+                f,
+                "{} transactions exceed the limit of {}",
+                count, MAX_BLOCK_TRANSACTIONS
+            ),
+            InvalidBlock::TooLarge(size) => write!(
+                f,
+                "{} bytes exceed the block size limit of {}",
+                size, MAX_BLOCK_SIZE
+            ),
+            InvalidBlock::DifficultyMismatch { expected, actual } => {
+                write!(f, "difficulty is {} but {} is expected", actual, expected)
+            }
+            InvalidBlock::InvalidProofOfWork => write!(f, "nonce does not satisfy the difficulty"),
+            InvalidBlock::TimestampTooOld => {
+                write!(
+                    f,
+                    "timestamp is not newer than the median of the last blocks"
+                )
+            }
+            InvalidBlock::TimestampTooFarInFuture => {
+                write!(f, "timestamp is too far in the future")
+            }
+            InvalidBlock::UnexpectedSigner => write!(f, "signer is not the one in turn"),
+            InvalidBlock::InvalidSignature => write!(f, "signature of the signer is invalid"),
+            InvalidBlock::MerkleRootMismatch => {
+                write!(f, "Merkle root does not match the transactions")
+            }
+            InvalidBlock::MissingCoinbase => {
+                write!(f, "first transaction is not a coinbase transaction")
+            }
+            InvalidBlock::CoinbaseValueMismatch { expected, actual } => {
+                write!(f, "coinbase pays {} but {} is expected", actual, expected)
+            }
+            InvalidBlock::MalformedTransaction(i) => write!(f, "transaction #{} is malformed", i),
+            InvalidBlock::Overdraft(i) => {
+                write!(f, "transaction #{} spends more than its sender owns", i)
+            }
+            InvalidBlock::NonceMismatch(i) => write!(
+                f,
+                "transaction #{} does not have the next nonce of its sender",
+                i
+            ),
+            InvalidBlock::InvalidTransactionSignature(i) => write!(
+                f,
+                "transaction #{} is not signed by the owner of its coins",
+                i
+            ),
+            InvalidBlock::DoubleSpend(i) => write!(
+                f,
+                "transaction #{} spends an output which is unknown or already spent",
+                i
+            ),
+            InvalidBlock::UnbalancedTransaction(i) => write!(
+                f,
+                "inputs of transaction #{} do not add up to its outputs and fee",
+                i
+            ),
+        }
+    }
+}
+
+/// Error telling which block of a chain failed validation and why.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    pub height: usize,
+    pub reason: InvalidBlock,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "block at height {} is invalid: {}",
+            self.height, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Validate a block which is going to be appended to `chain`.
+/// `ledger` is the ownership of coins at the end of `chain`, to which the block is
+/// connected if it is valid. An invalid block leaves it as it was.
+/// `now` is the network-adjusted time against which future timestamps are checked.
+pub fn validate_block(
+    block: &Block,
+    chain: &Ancestors,
+    now: i64,
+    consensus: &dyn Consensus,
+    reward_spec: &RewardSpec,
+    ledger: &mut Ledger,
+) -> Result<(), InvalidBlock> {
+    let parent = chain.last().ok_or(InvalidBlock::GenesisMismatch)?;
+    if block.header.previous_block_hash != Some(parent.header.hash()) {
+        return Err(InvalidBlock::PreviousHashMismatch);
+    }
+
+    let count = block.transactions().len();
+    if count > MAX_BLOCK_TRANSACTIONS {
+        return Err(InvalidBlock::TooManyTransactions(count));
+    }
+    let size = block.serialized_size();
+    if size > MAX_BLOCK_SIZE {
+        return Err(InvalidBlock::TooLarge(size));
+    }
+
+    consensus.verify_seal(block, chain)?;
+
+    if block.header.timestamp <= timestamp::median_time_past(chain) {
+        return Err(InvalidBlock::TimestampTooOld);
+    }
+    if block.header.timestamp > now + MAX_FUTURE_DRIFT {
+        return Err(InvalidBlock::TimestampTooFarInFuture);
+    }
+
+    if block.header.merkle_root != merkle::compute_root(block.transactions()) {
+        return Err(InvalidBlock::MerkleRootMismatch);
+    }
+
+    let coinbase = block
+        .transactions()
+        .first()
+        .and_then(|x| serde_json::from_str::<Transaction>(x).ok())
+        .filter(|x| x.is_coinbase() && !x.get_recipient().is_empty())
+        .ok_or(InvalidBlock::MissingCoinbase)?;
+    for (i, txn) in block.transactions().iter().enumerate().skip(1) {
+        let is_valid = match serde_json::from_str::<Transaction>(txn) {
+            Ok(txn) if txn.get_vote().is_some() => consensus.verify_vote(block, &txn),
+            Ok(txn) if txn.is_well_formed() && !txn.has_valid_signature() => {
+                return Err(InvalidBlock::InvalidTransactionSignature(i));
+            }
+            _ => ledger.model().is_well_formed(txn),
+        };
+        if !is_valid {
+            return Err(InvalidBlock::MalformedTransaction(i));
+        }
+    }
+    // coinbaseが支払えるのは、その高さの発行額とブロック内の手数料の合計だけ
+    let fees = ledger.connect_block(block)?;
+    let expected = i64::from(reward::block_subsidy(reward_spec, chain.next_height())) + fees;
+    let actual = i64::from(coinbase.get_value());
+    if actual != expected {
+        ledger.disconnect_block(block);
+        return Err(InvalidBlock::CoinbaseValueMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockHeader;
+    use crate::blockchain::ledger::LedgerModel;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::crypt::keys::KeyPair;
+
+    /// Engine accepting any seal, so that only the other rules are checked.
+    struct Unsealed;
+
+    impl Consensus for Unsealed {
+        fn prepare_header(&self, _header: &mut BlockHeader, _chain: &Ancestors) {}
+
+        fn seal(&self, _block: &mut Block) -> bool {
+            true
+        }
+
+        fn verify_seal(&self, _block: &Block, _chain: &Ancestors) -> Result<(), InvalidBlock> {
+            Ok(())
+        }
+
+        fn block_weight(&self, _header: &BlockHeader) -> u128 {
+            1
+        }
+
+        fn abort(&self) {}
+
+        fn stop(&self) {}
+
+        fn is_stopped(&self) -> bool {
+            false
+        }
+    }
+
+    fn genesis() -> Block {
+        ChainSpec::default().genesis_block()
+    }
+
+    fn next_block(coinbase_value: i32, transactions: Vec<String>) -> Block {
+        let genesis = genesis();
+        let header = BlockHeader::new(genesis.header.hash(), genesis.header.timestamp + 10);
+        let coinbase = Transaction::new_coinbase("miner", coinbase_value).to_string();
+        Block::assemble(header, coinbase, transactions)
+    }
+
+    fn validate(block: &Block) -> Result<(), InvalidBlock> {
+        let chain = vec![genesis()];
+        let mut ledger = Ledger::replay(LedgerModel::Account, &chain).unwrap();
+        let now = chain[0].header.timestamp + 60;
+        validate_block(
+            block,
+            &Ancestors::of(&chain),
+            now,
+            &Unsealed,
+            &RewardSpec::default(),
+            &mut ledger,
+        )
+    }
+
+    #[test]
+    fn block_paying_the_subsidy_is_valid() {
+        assert_eq!(validate(&next_block(50, Vec::new())), Ok(()));
+    }
+
+    #[test]
+    fn block_on_another_parent_is_rejected() {
+        let mut block = next_block(50, Vec::new());
+        block.header.previous_block_hash = Some("0".repeat(64));
+        assert_eq!(validate(&block), Err(InvalidBlock::PreviousHashMismatch));
+    }
+
+    #[test]
+    fn blocks_beyond_the_limits_are_rejected() {
+        let genesis = genesis();
+        let block = |transactions| {
+            Block::new(
+                transactions,
+                Some(genesis.header.hash()),
+                genesis.header.difficulty,
+                genesis.header.timestamp + 10,
+            )
+        };
+        let count = MAX_BLOCK_TRANSACTIONS + 1;
+        assert_eq!(
+            validate(&block(vec!["x".to_string(); count])),
+            Err(InvalidBlock::TooManyTransactions(count))
+        );
+        let large = block(vec!["x".repeat(MAX_BLOCK_SIZE)]);
+        assert_eq!(
+            validate(&large),
+            Err(InvalidBlock::TooLarge(large.serialized_size()))
+        );
+    }
+
+    #[test]
+    fn timestamps_out_of_range_are_rejected() {
+        let mut block = next_block(50, Vec::new());
+        block.header.timestamp = genesis().header.timestamp;
+        assert_eq!(validate(&block), Err(InvalidBlock::TimestampTooOld));
+        block.header.timestamp = genesis().header.timestamp + 60 + MAX_FUTURE_DRIFT + 1;
+        assert_eq!(validate(&block), Err(InvalidBlock::TimestampTooFarInFuture));
+    }
+
+    #[test]
+    fn transactions_not_committed_to_by_the_header_are_rejected() {
+        let mut block = next_block(50, Vec::new());
+        block.header.merkle_root = merkle::compute_root(&[]);
+        assert_eq!(validate(&block), Err(InvalidBlock::MerkleRootMismatch));
+    }
+
+    #[test]
+    fn coinbase_must_come_first_and_pay_the_reward() {
+        assert_eq!(
+            validate(&next_block(51, Vec::new())),
+            Err(InvalidBlock::CoinbaseValueMismatch {
+                expected: 50,
+                actual: 51
+            })
+        );
+        let payment = Transaction::new_signed(&KeyPair::from_seed("alice"), "bob", 1, 1, 0);
+        let genesis = genesis();
+        let block = Block::new(
+            vec![payment.to_string()],
+            Some(genesis.header.hash()),
+            genesis.header.difficulty,
+            genesis.header.timestamp + 10,
+        );
+        assert_eq!(validate(&block), Err(InvalidBlock::MissingCoinbase));
+    }
+
+    #[test]
+    fn tampered_or_unfunded_transactions_are_rejected() {
+        let payment = Transaction::new_signed(&KeyPair::from_seed("nobody"), "bob", 10, 1, 0);
+        let tampered = payment.to_string().replace("\"value\":10", "\"value\":11");
+        assert_eq!(
+            validate(&next_block(50, vec![tampered])),
+            Err(InvalidBlock::InvalidTransactionSignature(1))
+        );
+        assert_eq!(
+            validate(&next_block(51, vec![payment.to_string()])),
+            Err(InvalidBlock::Overdraft(1))
+        );
+    }
+}
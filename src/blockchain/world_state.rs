@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use crate::blockchain::ledger;
+use crate::blockchain::validation::InvalidBlock;
+use crate::{Block, Transaction};
+
+/// Balance and nonce of every address, derived by replaying the transactions of a chain.
+#[derive(Debug, Default)]
+pub struct WorldState {
+    balances: HashMap<String, i64>,
+    /// Nonce the next transaction sent from each address must have.
+    nonces: HashMap<String, u64>,
+}
+
+/// Changes made by the transactions of a block which are not applied yet.
+#[derive(Default)]
+struct Changes {
+    balances: HashMap<String, i64>,
+    nonces: HashMap<String, u64>,
+}
+
+impl WorldState {
+    /// Apply every transaction of a block, or none of them if one overdraws its sender
+    /// or is out of the order of its sender's nonces. Returns the fees paid in the block.
+    pub fn apply_block(&mut self, block: &Block) -> Result<i64, InvalidBlock> {
+        let mut changes = Changes::default();
+        let mut fees = 0;
+        for (i, txn) in block.transactions().iter().enumerate() {
+            let txn = serde_json::from_str::<Transaction>(txn)
+                .ok()
+                .filter(|x| x.is_coinbase() == ledger::is_coinbase_position(block, i))
+                .ok_or(InvalidBlock::MalformedTransaction(i))?;
+            fees += self.transfer(&mut changes, &txn, i)?;
+        }
+        self.commit(changes);
+        Ok(fees)
+    }
+
+    /// Undo `apply_block` for the last applied block.
+    pub fn revert_block(&mut self, block: &Block) {
+        for txn in block.transactions().iter().rev() {
+            let txn = match serde_json::from_str::<Transaction>(txn) {
+                Ok(txn) if txn.get_vote().is_none() => txn,
+                _ => continue,
+            };
+            let value = i64::from(txn.get_value());
+            *self
+                .balances
+                .entry(txn.get_recipient().to_string())
+                .or_default() -= value;
+            if !txn.is_coinbase() {
+                *self
+                    .balances
+                    .entry(txn.get_sender().to_string())
+                    .or_default() += value + i64::from(txn.get_fee());
+                self.nonces
+                    .insert(txn.get_sender().to_string(), txn.get_nonce());
+            }
+        }
+    }
+
+    /// Start applying transactions on top of this state without changing it.
+    pub fn draft(&self) -> Draft<'_> {
+        Draft {
+            state: self,
+            changes: Changes::default(),
+        }
+    }
+
+    /// Record the changes made by `txn`, the transaction at position `i` of a block,
+    /// on top of those already in `changes`, and return the fee it pays.
+    fn transfer(
+        &self,
+        changes: &mut Changes,
+        txn: &Transaction,
+        i: usize,
+    ) -> Result<i64, InvalidBlock> {
+        // 投票はコインを動かさない
+        if txn.get_vote().is_some() {
+            return Ok(0);
+        }
+        let value = i64::from(txn.get_value());
+        let fee = i64::from(txn.get_fee());
+        if !txn.is_coinbase() {
+            let sender = txn.get_sender();
+            let nonce = changes
+                .nonces
+                .get(sender)
+                .copied()
+                .unwrap_or_else(|| self.get_next_nonce(sender));
+            if txn.get_nonce() != nonce {
+                return Err(InvalidBlock::NonceMismatch(i));
+            }
+            let balance = self.balance_with(changes, sender);
+            if balance < value + fee {
+                return Err(InvalidBlock::Overdraft(i));
+            }
+            changes
+                .balances
+                .insert(sender.to_string(), balance - value - fee);
+            changes.nonces.insert(sender.to_string(), nonce + 1);
+        }
+        let balance = self.balance_with(changes, txn.get_recipient());
+        changes
+            .balances
+            .insert(txn.get_recipient().to_string(), balance + value);
+        // 手数料は送り手から引かれ、coinbaseでマイナーに支払われる
+        Ok(if txn.is_coinbase() { 0 } else { fee })
+    }
+
+    fn commit(&mut self, changes: Changes) {
+        self.balances.extend(changes.balances);
+        self.nonces.extend(changes.nonces);
+    }
+
+    fn balance_with(&self, changes: &Changes, address: &str) -> i64 {
+        changes
+            .balances
+            .get(address)
+            .copied()
+            .unwrap_or_else(|| self.get_balance(address))
+    }
+
+    /// Coins owned by the address. Unknown addresses own nothing.
+    pub fn get_balance(&self, address: &str) -> i64 {
+        self.balances.get(address).copied().unwrap_or(0)
+    }
+
+    /// Nonce the next transaction sent from the address must have.
+    pub fn get_next_nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).copied().unwrap_or(0)
+    }
+}
+
+/// Transactions applied on top of a world state, which is left as it is.
+pub struct Draft<'a> {
+    state: &'a WorldState,
+    changes: Changes,
+}
+
+impl Draft<'_> {
+    /// Apply a single transaction if it is signed by its sender, has the next nonce
+    /// of the sender and the sender can afford it. Returns the fee it pays.
+    pub fn apply_transaction(&mut self, txn: &Transaction) -> Option<i64> {
+        if !txn.has_valid_signature() {
+            return None;
+        }
+        // 失敗した取引は何も書き込まないので、そのまま次の取引を試せる
+        self.state.transfer(&mut self.changes, txn, 0).ok()
+    }
+}
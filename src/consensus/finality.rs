@@ -0,0 +1,180 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::crypt::keys::{self, KeyPair};
+
+/// Validators of the BFT finality layer, given in the chain spec.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FinalitySpec {
+    /// Public keys of the validators.
+    pub validators: Vec<String>,
+}
+
+/// Block which can no longer be reverted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: usize,
+    pub block_hash: String,
+}
+
+/// Round of voting on a block. A validator commits after seeing a quorum of prepares,
+/// and the block is final after a quorum of commits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Prepare,
+    Commit,
+}
+
+/// Signed vote of a validator on a block.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FinalityVote {
+    pub block_hash: String,
+    pub height: usize,
+    pub validator: String,
+    pub signature: String,
+}
+
+impl FinalityVote {
+    fn new(phase: Phase, block_hash: &str, height: usize, key: &KeyPair) -> FinalityVote {
+        FinalityVote {
+            block_hash: block_hash.to_string(),
+            height,
+            validator: key.public_key(),
+            signature: key.sign(&signing_string(phase, block_hash, height)),
+        }
+    }
+
+    /// Check the signature. The phase is signed too, so a prepare cannot be replayed as a commit.
+    fn verify(&self, phase: Phase) -> bool {
+        keys::verify(
+            &self.validator,
+            &signing_string(phase, &self.block_hash, self.height),
+            &self.signature,
+        )
+    }
+}
+
+fn signing_string(phase: Phase, block_hash: &str, height: usize) -> String {
+    format!("{:?}:{}:{}", phase, block_hash, height)
+}
+
+/// Votes collected by a core node.
+pub struct Finality {
+    validators: BTreeSet<String>,
+    key: Option<KeyPair>,
+    /// Validators that voted, and the height of the block, for each phase and block hash.
+    votes: HashMap<(Phase, String), (usize, HashSet<String>)>,
+    /// Phases and blocks whose quorum was already reached, so that it is acted on only once.
+    reached: HashSet<(Phase, String)>,
+    /// Block this node voted on at each height, so that it never votes on a conflicting one.
+    locks: BTreeMap<usize, String>,
+}
+
+impl Finality {
+    /// `key` makes this node vote if it belongs to one of the validators.
+    pub fn new(spec: &FinalitySpec, key: Option<KeyPair>) -> anyhow::Result<Finality> {
+        if let Some(validator) = spec.validators.iter().find(|x| !keys::is_public_key(x)) {
+            return Err(anyhow!("validator {} is not a public key", validator));
+        }
+        let validators: BTreeSet<String> = spec.validators.iter().cloned().collect();
+        let key = key.filter(|x| {
+            let is_validator = validators.contains(&x.public_key());
+            if !is_validator {
+                eprintln!("validator key is not one of the validators, so this node does not vote");
+            }
+            is_validator
+        });
+        Ok(Finality {
+            validators,
+            key,
+            votes: HashMap::new(),
+            reached: HashSet::new(),
+            locks: BTreeMap::new(),
+        })
+    }
+
+    /// Sign our vote on a block, if this node is a validator and has not voted on
+    /// another block at the same height in either phase.
+    pub fn sign(&mut self, phase: Phase, block_hash: &str, height: usize) -> Option<FinalityVote> {
+        let key = self.key.as_ref()?;
+        // 同じ高さで別のブロックに投票すると、食い違う2つのブロックが両方確定しうる
+        let locked = self
+            .locks
+            .entry(height)
+            .or_insert_with(|| block_hash.to_string());
+        if locked != block_hash {
+            println!(
+                "Do not vote on {} since we already voted on {} at height {}",
+                block_hash, locked, height
+            );
+            return None;
+        }
+        Some(FinalityVote::new(phase, block_hash, height, key))
+    }
+
+    /// Check that a vote was signed by one of the validators.
+    pub fn is_valid(&self, phase: Phase, vote: &FinalityVote) -> bool {
+        self.validators.contains(&vote.validator) && vote.verify(phase)
+    }
+
+    /// Record a valid vote. Votes may arrive before the block itself.
+    pub fn add_vote(&mut self, phase: Phase, vote: &FinalityVote) {
+        self.votes
+            .entry((phase, vote.block_hash.clone()))
+            .or_insert_with(|| (vote.height, HashSet::new()))
+            .1
+            .insert(vote.validator.clone());
+    }
+
+    /// Return true if more than two thirds of the validators voted on the block
+    /// and this has not been reported before.
+    pub fn reach(&mut self, phase: Phase, block_hash: &str) -> bool {
+        let key = (phase, block_hash.to_string());
+        let count = self.votes.get(&key).map_or(0, |(_, voters)| voters.len());
+        count * 3 > self.validators.len() * 2 && self.reached.insert(key)
+    }
+
+    /// Forget the votes on blocks at or below the finalized height.
+    pub fn prune(&mut self, finalized_height: usize) {
+        self.votes
+            .retain(|_, (height, _)| *height > finalized_height);
+        let votes = &self.votes;
+        self.reached.retain(|x| votes.contains_key(x));
+        self.locks = self.locks.split_off(&(finalized_height + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finality(key: &KeyPair) -> Finality {
+        let spec = FinalitySpec {
+            validators: vec![key.public_key()],
+        };
+        Finality::new(&spec, Some(key.clone())).unwrap()
+    }
+
+    #[test]
+    fn validator_never_votes_on_conflicting_blocks_at_a_height() {
+        let key = KeyPair::from_seed("validator");
+        let mut finality = finality(&key);
+        assert!(finality.sign(Phase::Prepare, "a", 1).is_some());
+        assert!(finality.sign(Phase::Commit, "a", 1).is_some());
+        assert!(finality.sign(Phase::Prepare, "b", 1).is_none());
+        assert!(finality.sign(Phase::Commit, "b", 1).is_none());
+        assert!(finality.sign(Phase::Prepare, "b", 2).is_some());
+    }
+
+    #[test]
+    fn locks_at_finalized_heights_are_pruned() {
+        let key = KeyPair::from_seed("validator");
+        let mut finality = finality(&key);
+        assert!(finality.sign(Phase::Prepare, "a", 1).is_some());
+        assert!(finality.sign(Phase::Prepare, "c", 2).is_some());
+        finality.prune(1);
+        assert!(finality.sign(Phase::Prepare, "b", 1).is_some());
+        assert!(finality.sign(Phase::Prepare, "d", 2).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+pub mod finality;
+pub mod poa;
+pub mod pow;
+
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+
+use crate::blockchain::ancestors::Ancestors;
+use crate::blockchain::block::BlockHeader;
+use crate::blockchain::validation::InvalidBlock;
+use crate::consensus::poa::ProofOfAuthority;
+use crate::consensus::pow::ProofOfWork;
+use crate::crypt::keys::{self, KeyPair};
+use crate::transaction::pool::SignerVote;
+use crate::{Block, Transaction};
+
+/// State an engine derives from a block and the state after its parent, such as the
+/// signer set of Proof of Authority. It is kept with the block in the block tree,
+/// so that it never has to be derived again from the whole chain.
+pub type EngineState = Option<Arc<dyn Any + Send + Sync>>;
+
+/// Position of a branch of the block tree, on which fork choice is decided.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainScore {
+    pub height: usize,
+    /// Sum of the weights of the blocks of the branch.
+    pub total_weight: u128,
+}
+
+/// Rules deciding how blocks are sealed and which chain a node follows.
+pub trait Consensus: Send + Sync {
+    /// Fill the consensus fields of the header of a block to be appended to `chain`.
+    /// An abort after this call gives up sealing the block.
+    fn prepare_header(&self, header: &mut BlockHeader, chain: &Ancestors);
+    /// Transactions the engine puts right after the coinbase transaction of a new block.
+    fn prepare_transactions(&self, _chain: &Ancestors) -> Vec<String> {
+        Vec::new()
+    }
+    /// Seal a block so that peers accept it.
+    /// Return false if sealing was aborted or stopped before it completed.
+    fn seal(&self, block: &mut Block) -> bool;
+    /// Check the consensus fields and the seal of a block to be appended to `chain`.
+    fn verify_seal(&self, block: &Block, chain: &Ancestors) -> Result<(), InvalidBlock>;
+    /// Weight a block adds to its branch.
+    fn block_weight(&self, header: &BlockHeader) -> u128;
+    /// State after a validated block, given the state after its parent.
+    /// `parent` is `None` for the genesis block. Engines without state keep none.
+    fn next_state(&self, _block: &Block, _parent: &EngineState) -> EngineState {
+        None
+    }
+    /// Check a governance transaction in a block. Engines without governance reject all.
+    fn verify_vote(&self, _block: &Block, _vote: &Transaction) -> bool {
+        false
+    }
+
+    /// Decide which of two branches to follow. `Ordering::Greater` prefers `a`.
+    fn compare_chains(&self, a: &ChainScore, b: &ChainScore) -> Ordering {
+        a.total_weight.cmp(&b.total_weight)
+    }
+
+    /// Give up the block currently being sealed or prepared, e.g. because the chain moved on.
+    fn abort(&self);
+    /// Stop sealing blocks for good.
+    fn stop(&self);
+    fn is_stopped(&self) -> bool;
+}
+
+/// Consensus engine selected by the chain spec.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "engine", rename_all = "snake_case")]
+pub enum ConsensusSpec {
+    #[default]
+    ProofOfWork,
+    ProofOfAuthority {
+        /// Public keys of the signers at the genesis block.
+        signers: Vec<String>,
+    },
+}
+
+/// Settings of this node for sealing blocks, which differ from node to node.
+#[derive(Clone, Default)]
+pub struct SealerConfig {
+    /// Key to sign blocks with. (Proof of Authority)
+    pub signer_key: Option<KeyPair>,
+    /// Changes of the signer set to vote for, as pairs of a public key and a vote.
+    pub votes: Vec<(String, SignerVote)>,
+}
+
+impl ConsensusSpec {
+    pub fn build(&self, config: SealerConfig) -> anyhow::Result<Arc<dyn Consensus>> {
+        match self {
+            ConsensusSpec::ProofOfWork => Ok(Arc::new(ProofOfWork::new())),
+            ConsensusSpec::ProofOfAuthority { signers } => {
+                if signers.is_empty() {
+                    return Err(anyhow!("Proof of Authority needs at least one signer"));
+                }
+                if let Some(signer) = signers.iter().find(|x| !keys::is_public_key(x)) {
+                    return Err(anyhow!("signer {} is not a public key", signer));
+                }
+                Ok(Arc::new(ProofOfAuthority::new(signers, config)))
+            }
+        }
+    }
+}
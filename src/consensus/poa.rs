@@ -0,0 +1,305 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::blockchain::ancestors::Ancestors;
+use crate::blockchain::block::BlockHeader;
+use crate::blockchain::validation::InvalidBlock;
+use crate::consensus::{Consensus, EngineState, SealerConfig};
+use crate::crypt::keys::{self, KeyPair};
+use crate::transaction::pool::SignerVote;
+use crate::{Block, Transaction};
+
+/// Proof of Authority: the configured signers take turns in sealing blocks with their keys.
+pub struct ProofOfAuthority {
+    genesis_signers: BTreeSet<String>,
+    key: Option<KeyPair>,
+    /// Changes of the signer set this node votes for while it is a signer.
+    votes: Vec<(String, SignerVote)>,
+    stopped: AtomicBool,
+}
+
+/// Signer set after a block, and the votes which have not changed it yet.
+#[derive(Clone, Debug)]
+struct SignerState {
+    signers: BTreeSet<String>,
+    tally: HashMap<(String, SignerVote), HashSet<String>>,
+}
+
+impl SignerState {
+    fn new(signers: BTreeSet<String>) -> SignerState {
+        SignerState {
+            signers,
+            tally: HashMap::new(),
+        }
+    }
+
+    /// Count the governance transactions of a block.
+    fn apply(&mut self, block: &Block) {
+        let votes = block
+            .transactions()
+            .iter()
+            .filter_map(|x| serde_json::from_str::<Transaction>(x).ok());
+        for txn in votes {
+            let vote = match txn.get_vote() {
+                Some(vote) => vote,
+                None => continue,
+            };
+            let candidate = txn.get_recipient().to_string();
+            let voters = self.tally.entry((candidate.clone(), vote)).or_default();
+            voters.insert(txn.get_sender().to_string());
+
+            // 現在の署名者の過半数が同じ変更に投票したときに反映する
+            let signers = &self.signers;
+            if voters.iter().filter(|x| signers.contains(*x)).count() * 2 <= signers.len() {
+                continue;
+            }
+            match vote {
+                SignerVote::Add => {
+                    self.signers.insert(candidate.clone());
+                }
+                // 署名者がいなくなるとチェーンが止まってしまうので、最後の1人は外さない
+                SignerVote::Remove if self.signers.len() > 1 => {
+                    self.signers.remove(&candidate);
+                }
+                SignerVote::Remove => {}
+            };
+            self.tally.retain(|(x, _), _| *x != candidate);
+        }
+    }
+}
+
+impl ProofOfAuthority {
+    pub fn new(signers: &[String], config: SealerConfig) -> ProofOfAuthority {
+        ProofOfAuthority {
+            genesis_signers: signers.iter().cloned().collect(),
+            key: config.signer_key,
+            votes: config.votes,
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Signer set in effect for the block following `chain`,
+    /// i.e. the genesis signers changed by the governance transactions of the chain.
+    /// It is read from the state after the parent, or counted from the genesis block
+    /// if `chain` has no state but reaches back to it. Otherwise the signer set is unknown.
+    pub fn signers(&self, chain: &Ancestors) -> Result<BTreeSet<String>, InvalidBlock> {
+        let state = chain
+            .state()
+            .as_ref()
+            .and_then(|x| x.downcast_ref::<SignerState>());
+        match state {
+            Some(state) => Ok(state.signers.clone()),
+            None if chain.first_height() == 0 => {
+                let mut state = SignerState::new(self.genesis_signers.clone());
+                for block in chain.recent() {
+                    state.apply(block);
+                }
+                Ok(state.signers)
+            }
+            None => Err(InvalidBlock::UnexpectedSigner),
+        }
+    }
+
+    /// Signer whose turn it is to seal the block following `chain`.
+    fn signer_in_turn(&self, chain: &Ancestors) -> Result<String, InvalidBlock> {
+        let signers = self.signers(chain)?;
+        Ok(signers
+            .iter()
+            .nth(chain.next_height() % signers.len())
+            .cloned()
+            .unwrap())
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn prepare_header(&self, header: &mut BlockHeader, chain: &Ancestors) {
+        // 署名者が分からなければ空のままにし、封印しない
+        header.signer = self.signer_in_turn(chain).ok();
+    }
+
+    fn prepare_transactions(&self, chain: &Ancestors) -> Vec<String> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Vec::new(),
+        };
+        let signers = match self.signers(chain) {
+            Ok(signers) => signers,
+            Err(_) => return Vec::new(),
+        };
+        self.votes
+            .iter()
+            .filter(|(candidate, vote)| match vote {
+                SignerVote::Add => !signers.contains(candidate),
+                SignerVote::Remove => signers.contains(candidate),
+            })
+            .map(|(candidate, vote)| Transaction::new_vote(key.public_key(), candidate, *vote))
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    fn seal(&self, block: &mut Block) -> bool {
+        if self.is_stopped() {
+            return false;
+        }
+        let key = match &self.key {
+            Some(key) => key,
+            None => {
+                println!("No signer key is given, so this node does not seal blocks ...");
+                return false;
+            }
+        };
+        if block.header.signer.as_deref() != Some(key.public_key().as_str()) {
+            println!("It is not our turn to seal a block ...");
+            return false;
+        }
+        block.header.signature = Some(key.sign(&block.header.to_unsealed_string()));
+        true
+    }
+
+    fn verify_seal(&self, block: &Block, chain: &Ancestors) -> Result<(), InvalidBlock> {
+        let expected = self.signer_in_turn(chain)?;
+        let signer = match &block.header.signer {
+            Some(signer) if *signer == expected => signer,
+            _ => return Err(InvalidBlock::UnexpectedSigner),
+        };
+        let valid = match (&block.header.nonce, &block.header.signature) {
+            (None, Some(signature)) => {
+                keys::verify(signer, &block.header.to_unsealed_string(), signature)
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(InvalidBlock::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// Only the signer in turn may seal a block, so the longest chain is followed.
+    fn block_weight(&self, _header: &BlockHeader) -> u128 {
+        1
+    }
+
+    /// The signer set and the pending votes after the block.
+    fn next_state(&self, block: &Block, parent: &EngineState) -> EngineState {
+        let parent = parent
+            .as_ref()
+            .and_then(|x| x.downcast_ref::<SignerState>());
+        let mut state = match parent {
+            Some(state) => state.clone(),
+            None => SignerState::new(self.genesis_signers.clone()),
+        };
+        state.apply(block);
+        Some(Arc::new(state))
+    }
+
+    /// A signer may only vote in the blocks it seals.
+    fn verify_vote(&self, block: &Block, vote: &Transaction) -> bool {
+        block.header.signer.as_deref() == Some(vote.get_sender())
+            && keys::is_public_key(vote.get_recipient())
+            && vote.get_value() == 0
+    }
+
+    /// Sealing does not take long, so there is nothing to abort.
+    fn abort(&self) {}
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::ancestors::LOOKBACK;
+    use crate::blockchain::tree::BlockTree;
+
+    fn engine(signers: &[String]) -> ProofOfAuthority {
+        ProofOfAuthority::new(signers, SealerConfig::default())
+    }
+
+    /// Chain in which `voter` votes on `candidate` in every block after the genesis block.
+    fn chain(voter: &str, candidate: &str, vote: SignerVote, len: usize) -> Vec<Block> {
+        let mut chain = vec![Block::new(Vec::new(), None, 0, 0)];
+        for i in 1..len {
+            let transactions = vec![
+                Transaction::new_coinbase("miner", 0).to_string(),
+                Transaction::new_vote(voter, candidate, vote).to_string(),
+            ];
+            let parent = chain[i - 1].header.hash();
+            chain.push(Block::new(transactions, Some(parent), 0, i as i64));
+        }
+        chain
+    }
+
+    /// Block tree of `chain`, holding the state `poa` derives after each block.
+    fn tree(poa: &ProofOfAuthority, chain: &[Block]) -> BlockTree {
+        let mut tree = BlockTree::new(chain[0].clone(), 1, poa.next_state(&chain[0], &None));
+        for block in &chain[1..] {
+            let parent = block.header.previous_block_hash.as_ref().unwrap();
+            let state = poa.next_state(block, tree.ancestors(parent).state());
+            tree.insert(block.clone(), 1, state);
+        }
+        tree
+    }
+
+    #[test]
+    fn majority_vote_changes_the_signer_set() {
+        let a = KeyPair::from_seed("a").public_key();
+        let b = KeyPair::from_seed("b").public_key();
+        let poa = engine(std::slice::from_ref(&a));
+        let chain = chain(&a, &b, SignerVote::Add, 2);
+        assert_eq!(
+            poa.signers(&Ancestors::of(&chain[..1])),
+            Ok(BTreeSet::from([a.clone()]))
+        );
+        assert_eq!(
+            poa.signers(&Ancestors::of(&chain)),
+            Ok(BTreeSet::from([a, b]))
+        );
+    }
+
+    #[test]
+    fn signer_set_of_a_long_chain_is_read_from_the_tree() {
+        let a = KeyPair::from_seed("a").public_key();
+        let b = KeyPair::from_seed("b").public_key();
+        let c = KeyPair::from_seed("c").public_key();
+        let signers = [a.clone(), b.clone()];
+        let mut chain = chain(&a, &c, SignerVote::Add, LOOKBACK + 5);
+        // 投票から LOOKBACK ブロック以上たってから、2人目が投票して過半数になる
+        let vote = Transaction::new_vote(b.as_str(), c.as_str(), SignerVote::Add);
+        let transactions = vec![
+            Transaction::new_coinbase("miner", 0).to_string(),
+            vote.to_string(),
+        ];
+        let parent = chain.last().unwrap().header.hash();
+        chain.push(Block::new(
+            transactions,
+            Some(parent),
+            0,
+            chain.len() as i64,
+        ));
+
+        let tree = tree(&engine(&signers), &chain);
+        let tip = chain.last().unwrap().header.hash();
+        // 状態は木に保存されているので、新しいエンジンでも同じ署名者の集合になる
+        assert_eq!(
+            engine(&signers).signers(&tree.ancestors(&tip)),
+            Ok(BTreeSet::from([a.clone(), b.clone(), c]))
+        );
+        let parent = chain[chain.len() - 2].header.hash();
+        assert_eq!(
+            engine(&signers).signers(&tree.ancestors(&parent)),
+            Ok(BTreeSet::from([a, b]))
+        );
+        // 状態もジェネシスからの祖先もなければ、署名者は分からない
+        assert_eq!(
+            engine(&signers).signers(&Ancestors::of(&chain)),
+            Err(InvalidBlock::UnexpectedSigner)
+        );
+    }
+}
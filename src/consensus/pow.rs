@@ -0,0 +1,113 @@
+use crate::blockchain::ancestors::Ancestors;
+use crate::blockchain::block::BlockHeader;
+use crate::blockchain::difficulty;
+use crate::blockchain::miner::Miner;
+use crate::blockchain::validation::InvalidBlock;
+use crate::consensus::Consensus;
+use crate::crypt::sha::get_double_sha256;
+use crate::Block;
+
+/// Proof of Work: the block hash must end with as many zeros as the difficulty.
+pub struct ProofOfWork {
+    miner: Miner,
+}
+
+impl ProofOfWork {
+    pub fn new() -> ProofOfWork {
+        ProofOfWork {
+            miner: Miner::new(),
+        }
+    }
+}
+
+impl Consensus for ProofOfWork {
+    fn prepare_header(&self, header: &mut BlockHeader, chain: &Ancestors) {
+        // 用意してから採掘を始めるまでに来た中断も、このブロックの採掘に効かせる
+        self.miner.clear_abort();
+        header.difficulty = difficulty::expected_difficulty(chain);
+    }
+
+    fn seal(&self, block: &mut Block) -> bool {
+        self.miner.mine(block).is_some()
+    }
+
+    fn verify_seal(&self, block: &Block, chain: &Ancestors) -> Result<(), InvalidBlock> {
+        let expected = difficulty::expected_difficulty(chain);
+        if block.header.difficulty != expected {
+            return Err(InvalidBlock::DifficultyMismatch {
+                expected,
+                actual: block.header.difficulty,
+            });
+        }
+        if !has_valid_proof_of_work(block) {
+            return Err(InvalidBlock::InvalidProofOfWork);
+        }
+        Ok(())
+    }
+
+    /// Expected number of hashes needed to mine the block.
+    fn block_weight(&self, header: &BlockHeader) -> u128 {
+        difficulty::block_work(header.difficulty)
+    }
+
+    fn abort(&self) {
+        self.miner.abort();
+    }
+
+    fn stop(&self) {
+        self.miner.stop();
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.miner.is_stopped()
+    }
+}
+
+/// ヘッダのnonceが、difficultyの桁数だけ末尾を0で揃えるハッシュ値を与えるかを確認する
+/// 署名はnonceの対象外なのにブロックのハッシュ値を変えてしまうので、付いていれば不正とする
+fn has_valid_proof_of_work(block: &Block) -> bool {
+    match (
+        block.header.nonce,
+        &block.header.signer,
+        &block.header.signature,
+    ) {
+        (Some(nonce), None, None) => {
+            let digest =
+                get_double_sha256(format!("{}{}", block.header.to_unsealed_string(), nonce));
+            digest.ends_with(&"0".repeat(block.header.difficulty))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mined_block() -> Block {
+        let mut block = Block::new(Vec::new(), Some("00".to_string()), 1, 0);
+        for nonce in 0.. {
+            block.header.nonce = Some(nonce);
+            if has_valid_proof_of_work(&block) {
+                break;
+            }
+        }
+        block
+    }
+
+    #[test]
+    fn mined_block_has_valid_proof_of_work() {
+        assert!(has_valid_proof_of_work(&mined_block()));
+    }
+
+    #[test]
+    fn signature_on_a_mined_block_is_rejected() {
+        let mut block = mined_block();
+        block.header.signature = Some("junk".to_string());
+        assert!(!has_valid_proof_of_work(&block));
+
+        let mut block = mined_block();
+        block.header.signer = Some("junk".to_string());
+        assert!(!has_valid_proof_of_work(&block));
+    }
+}
@@ -1,7 +1,10 @@
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
 
+use crate::core::clock::SystemClock;
 use crate::core::state::{get_my_addr, State};
 use crate::p2p::connection_manager::{ConnectionManager4Edge, Manager};
+use crate::transaction::utxo::UtxoTransaction;
 use crate::{MsgType, Transaction};
 
 pub struct Client {
@@ -11,7 +14,7 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(my_port: u16, core_addr: &'static str) -> Client {
+    pub fn new(my_port: u16, core_addr: &str, network_id: String) -> Client {
         println!("Initializing ClientCore ...");
         let my_addr = get_my_addr(my_port);
         println!("Server IP address is set to ... {}", my_addr);
@@ -20,7 +23,7 @@ impl Client {
         Client {
             server_state: State::Init,
             my_core_addr: core_addr,
-            cm: ConnectionManager4Edge::new(my_addr, core_addr),
+            cm: ConnectionManager4Edge::new(my_addr, core_addr, network_id, Arc::new(SystemClock)),
         }
     }
 
@@ -42,6 +45,31 @@ impl Client {
         println!("{}", msg_txt);
         self.cm.send_msg(&self.my_core_addr, msg_txt);
     }
+
+    /// Ask my core node to prove that `transaction` is included in a block.
+    /// Without `block_hash`, the core node searches its whole chain.
+    pub fn request_merkle_proof(&mut self, transaction: Transaction, block_hash: Option<String>) {
+        let msg_txt = self.cm.build_message_with_payload(
+            MsgType::RequestMerkleProof,
+            self.cm.addr,
+            Some(transaction),
+            block_hash,
+        );
+        println!("{}", msg_txt);
+        self.cm.send_msg(&self.my_core_addr, msg_txt);
+    }
+
+    /// Send a transaction of a UTXO ledger to my core node.
+    pub fn send_utxo_transaction(&mut self, transaction: UtxoTransaction) {
+        let msg_txt = self.cm.build_message_with_payload(
+            MsgType::NewUtxoTransaction,
+            self.cm.addr,
+            None,
+            Some(transaction.to_string()),
+        );
+        println!("{}", msg_txt);
+        self.cm.send_msg(&self.my_core_addr, msg_txt);
+    }
 }
 
 impl Drop for Client {
@@ -0,0 +1,118 @@
+use chrono::Utc;
+#[cfg(test)]
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Source of the current time and of waiting, so that tests can control both.
+pub trait Clock: Send + Sync {
+    /// Current time as a UNIX timestamp in seconds.
+    fn now(&self) -> i64;
+    /// Block the current thread until `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration);
+}
+
+/// Clock of the operating system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Clock which only moves forward when `advance` is called.
+/// Threads sleeping on it wake up once it has been advanced far enough.
+#[cfg(test)]
+pub struct MockClock {
+    state: Mutex<MockState>,
+    changed: Condvar,
+}
+
+#[cfg(test)]
+struct MockState {
+    now: Duration,
+    /// Number of calls of `sleep` so far, including those still sleeping.
+    sleeps: usize,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(start: i64) -> MockClock {
+        MockClock {
+            state: Mutex::new(MockState {
+                now: Duration::from_secs(start as u64),
+                sleeps: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().now += duration;
+        self.changed.notify_all();
+    }
+
+    /// Block until `sleep` has been called `count` times in total, so that the clock is
+    /// advanced only once the thread driven by it is waiting.
+    pub fn wait_for_sleeps(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.sleeps < count {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> i64 {
+        self.state.lock().unwrap().now.as_secs() as i64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.sleeps += 1;
+        self.changed.notify_all();
+        let deadline = state.now + duration;
+        while state.now < deadline {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+
+    #[test]
+    fn advance_moves_the_clock_forward() {
+        let clock = MockClock::new(100);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), 105);
+    }
+
+    #[test]
+    fn sleeper_wakes_up_once_the_clock_is_advanced_far_enough() {
+        let clock = Arc::new(MockClock::new(100));
+        let (tx, rx) = mpsc::channel();
+        {
+            let clock = clock.clone();
+            thread::spawn(move || {
+                clock.sleep(Duration::from_secs(10));
+                tx.send(clock.now()).unwrap();
+            });
+        }
+        // 眠り始める前に進めた分は数えられないので、眠ったのを待ってから進める
+        clock.wait_for_sleeps(1);
+        clock.advance(Duration::from_secs(9));
+        assert!(rx.try_recv().is_err());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(rx.recv().unwrap(), 110);
+    }
+}
@@ -1,10 +1,21 @@
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
+use crate::blockchain::chain::ChainEvent;
+use crate::blockchain::spec::ChainSpec;
+use crate::consensus::finality::Finality;
+use crate::consensus::{Consensus, SealerConfig};
+use crate::core::clock::{Clock, SystemClock};
 use crate::core::state::{get_my_addr, State};
+use crate::crypt::keys::KeyPair;
 use crate::p2p::connection_manager::{ConnectionManager, Manager};
-use crate::{Block, Blockchain, ToVecString};
+use crate::storage::file::FileStore;
+use crate::storage::memory::MemoryStore;
+use crate::storage::NodeStorage;
+use crate::{Blockchain, TransactionPool};
 
 const CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
@@ -13,63 +24,144 @@ pub struct Server {
     server_state: State,
     core_node_addr: Option<SocketAddr>,
     cm: ConnectionManager,
-    prev_block_hash: String,
-    bc: Blockchain,
+    bc: Blockchain<NodeStorage>,
+    consensus: Arc<dyn Consensus>,
+    miner_address: String,
+    clock: Arc<dyn Clock>,
+}
+
+/// Options given to a server on the command line.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Directory to persist the blockchains in, one subdirectory per network.
+    /// Kept only in memory if not given.
+    pub data_dir: Option<PathBuf>,
+    /// Definition of the network to join.
+    pub chain_spec: ChainSpec,
+    /// Address the block rewards are paid to.
+    pub miner_address: String,
+    /// Key and votes of this node for sealing blocks.
+    pub sealer: SealerConfig,
+    /// Key this node votes with if it is one of the finality validators.
+    pub validator_key: Option<KeyPair>,
+    /// Clock driving block timestamps, the mining schedule and pings.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl ServerConfig {
+    pub fn new(miner_address: String) -> ServerConfig {
+        ServerConfig {
+            data_dir: None,
+            chain_spec: ChainSpec::default(),
+            miner_address,
+            sealer: SealerConfig::default(),
+            validator_key: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
 }
 
 pub trait Overload<T> {
     fn new(_: T) -> Self;
 }
 
-impl Overload<u16> for Server {
-    fn new(my_port: u16) -> Self {
+impl Overload<(u16, ServerConfig)> for Server {
+    fn new(args: (u16, ServerConfig)) -> Self {
+        let (my_port, config) = args;
+
         println!("Initializing server ...");
         let my_addr = get_my_addr(my_port);
         println!("Server IP address is set to ... {}", my_addr);
 
-        let my_genesis_block = Block::new_genesis();
-        let bc = Blockchain::new(my_genesis_block.clone());
+        let (bc, tp) = init_state(&config);
 
         Server {
             server_state: State::Init,
             core_node_addr: None,
-            cm: ConnectionManager::new(my_addr),
-            prev_block_hash: bc.get_hash(&my_genesis_block),
+            cm: ConnectionManager::new(
+                my_addr,
+                bc.clone(),
+                tp,
+                config.clock.clone(),
+                init_finality(&config),
+            ),
+            consensus: bc.get_consensus(),
             bc,
+            miner_address: config.miner_address,
+            clock: config.clock,
         }
     }
 }
 
-impl Overload<(u16, &'static str)> for Server {
-    fn new(args: (u16, &'static str)) -> Self {
-        let my_port = args.0;
-        let node_addr = args.1.to_socket_addrs().unwrap().next().unwrap();
+impl Overload<(u16, &str, ServerConfig)> for Server {
+    fn new(args: (u16, &str, ServerConfig)) -> Self {
+        let (my_port, node_addr, config) = args;
+        let node_addr = node_addr.to_socket_addrs().unwrap().next().unwrap();
 
         println!("Initializing server ...");
         let my_addr = get_my_addr(my_port);
         println!("Server IP address is set to ... {}", my_addr);
 
-        let my_genesis_block = Block::new_genesis();
-        let bc = Blockchain::new(my_genesis_block.clone());
+        let (bc, tp) = init_state(&config);
 
         Server {
             server_state: State::Init,
             core_node_addr: Some(node_addr),
-            cm: ConnectionManager::new(my_addr),
-            prev_block_hash: bc.get_hash(&my_genesis_block),
+            cm: ConnectionManager::new(
+                my_addr,
+                bc.clone(),
+                tp,
+                config.clock.clone(),
+                init_finality(&config),
+            ),
+            consensus: bc.get_consensus(),
             bc,
+            miner_address: config.miner_address,
+            clock: config.clock,
         }
     }
 }
 
+/// Open the storage selected by the config and restore the chain and the pool from it.
+fn init_state(config: &ServerConfig) -> (Blockchain<NodeStorage>, TransactionPool<NodeStorage>) {
+    let spec = &config.chain_spec;
+    let storage: NodeStorage = match &config.data_dir {
+        Some(data_dir) => match FileStore::open(&spec.data_dir(data_dir)) {
+            Ok(store) => Box::new(store),
+            Err(e) => panic!("Failed to open the data directory: {:#}", e),
+        },
+        None => Box::new(MemoryStore::new()),
+    };
+    let storage = Arc::new(Mutex::new(storage));
+    let consensus = match spec.consensus.build(config.sealer.clone()) {
+        Ok(consensus) => consensus,
+        Err(e) => panic!("Failed to set up the consensus engine: {:#}", e),
+    };
+    let bc = match Blockchain::new(storage.clone(), spec, consensus, config.clock.clone()) {
+        Ok(bc) => bc,
+        Err(e) => panic!("Failed to load the blockchain: {:#}", e),
+    };
+    (bc, TransactionPool::new(storage))
+}
+
+/// Set up the BFT finality layer if the chain spec enables it.
+fn init_finality(config: &ServerConfig) -> Option<Finality> {
+    let spec = config.chain_spec.finality.as_ref()?;
+    match Finality::new(spec, config.validator_key.clone()) {
+        Ok(finality) => Some(finality),
+        Err(e) => panic!("Failed to set up the finality layer: {:#}", e),
+    }
+}
+
 impl Server {
     pub fn start(&mut self) {
         self.server_state = State::Standby;
         self.cm.start(self.cm.addr);
+        self.watch_chain_events();
 
         let mut self_clone = self.clone();
         thread::spawn(move || {
-            thread::sleep(CHECK_INTERVAL);
+            self_clone.clock.sleep(CHECK_INTERVAL);
             self_clone.generate_block_with_tp();
         });
     }
@@ -89,28 +181,92 @@ impl Server {
         };
     }
 
+    /// Keep the transaction pool and the block sealing in step with changes of the chain.
+    fn watch_chain_events(&self) {
+        let events = self.bc.subscribe();
+        let mut cm = self.cm.clone();
+        let consensus = self.consensus.clone();
+        thread::spawn(move || {
+            for event in events {
+                // 封印中のブロックは古いチェーンの先端を指しているので無駄になる
+                consensus.abort();
+                let mut tp = cm.tp.lock().unwrap();
+                let connected = match event {
+                    ChainEvent::Extended(block) => {
+                        tp.remove_included(block.transactions());
+                        vec![block]
+                    }
+                    ChainEvent::Reorganized {
+                        disconnected,
+                        connected,
+                    } => {
+                        // 切り離されたブロックのトランザクションは、再び採掘されるようプールに戻す
+                        for block in &disconnected {
+                            tp.restore_transactions(block.transactions());
+                        }
+                        for block in &connected {
+                            tp.remove_included(block.transactions());
+                        }
+                        connected
+                    }
+                };
+                drop(tp);
+                for block in &connected {
+                    cm.on_block_connected(&block.header.hash());
+                }
+            }
+        });
+    }
+
+    /// Stop mining and block generation.
+    pub fn shutdown(&mut self) {
+        self.server_state = State::ShuttingDown;
+        self.consensus.stop();
+    }
+
     fn generate_block_with_tp(&mut self) {
-        let mut tp_guard = self.cm.tp.lock().unwrap();
-        match tp_guard.get_stored_transactions() {
+        if self.consensus.is_stopped() {
+            println!("Block generation is stopped ...");
+            return;
+        }
+
+        // 採掘中も新しいトランザクションを受け付けられるように、プールのロックはすぐに手放す
+        let stored_transactions = self
+            .cm
+            .tp
+            .lock()
+            .unwrap()
+            .get_block_candidates(|x| self.bc.get_next_nonce(x));
+        match stored_transactions {
             Some(result) => {
-                let result_len = result.len();
-
-                let new_block =
-                    Block::new(result.to_vec_string(), Some(self.prev_block_hash.clone()));
-                self.bc.set_new_block(new_block.clone());
-                self.prev_block_hash = self.bc.get_hash(&new_block);
-                // ブロック生成に成功したらTransaction Poolはクリアする
-                tp_guard.clear_my_transactions(result_len);
+                // 手数料率の高い順に詰め、上限を超えた分はプールに残り、次のブロックに回る
+                let mut new_block = self.bc.prepare_block(&self.miner_address, result);
+                if self.consensus.seal(&mut new_block) {
+                    // 採掘中に他のCoreノードのブロックを受け入れていれば、このブロックは不正になる
+                    match self.bc.set_new_block(new_block.clone()) {
+                        // Transaction Poolのクリアは、ChainEventを受けてwatch_chain_eventsが行う
+                        Ok(()) => self.cm.broadcast_new_block(&new_block),
+                        Err(e) => eprintln!("Discard the mined block: {}", e),
+                    };
+                };
             }
             None => println!("Transaction Pool is empty ..."),
         };
 
-        println!("Current Blockchain is ... {:#?}", self.bc.get_chain());
-        println!("Current prev_block_hash is ... {}", self.prev_block_hash);
+        let height = self.bc.get_height();
+        println!("Current Blockchain height is ... {}", height);
+        if let Some(supply) = self.bc.get_issued_supply(height) {
+            println!("Current issued supply is ... {}", supply);
+        };
+        println!("Current tip is ... {:#?}", self.bc.get_tip());
+        println!(
+            "Current prev_block_hash is ... {}",
+            self.bc.get_last_block_hash()
+        );
 
         let mut self_clone = self.clone();
         thread::spawn(move || {
-            thread::sleep(CHECK_INTERVAL);
+            self_clone.clock.sleep(CHECK_INTERVAL);
             self_clone.generate_block_with_tp();
         });
     }
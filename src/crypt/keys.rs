@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Context};
+use crypto_hash::{digest, Algorithm};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey, SIGNATURE_LENGTH};
+use rand_core::OsRng;
+use std::fs;
+use std::path::Path;
+
+use crate::crypt::sha;
+
+/// Length of a signature encoded as hex.
+pub const SIGNATURE_HEX_LEN: usize = 2 * SIGNATURE_LENGTH;
+/// Length of an address in hex characters.
+const ADDRESS_HEX_LEN: usize = 40;
+
+/// Ed25519 key pair. Public keys and signatures are exchanged as hex strings.
+#[derive(Clone)]
+pub struct KeyPair {
+    signing_key: SigningKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> KeyPair {
+        KeyPair {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Derive a key from a well-known seed, so that demos can spend their coins.
+    /// Anyone knowing the seed can spend them too.
+    pub fn from_seed(seed: &str) -> KeyPair {
+        let secret: [u8; 32] = digest(Algorithm::SHA256, seed.as_bytes())
+            .try_into()
+            .expect("SHA-256 digest is 32 bytes");
+        KeyPair {
+            signing_key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Read a secret key written by `save`.
+    pub fn load(path: &Path) -> anyhow::Result<KeyPair> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read key file {}", path.display()))?;
+        let secret: [u8; 32] = hex::decode(content.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("key file {} is malformed", path.display()))?;
+        Ok(KeyPair {
+            signing_key: SigningKey::from_bytes(&secret),
+        })
+    }
+
+    /// Write the secret key as hex.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, hex::encode(self.signing_key.to_bytes()))
+            .with_context(|| format!("failed to write key file {}", path.display()))
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Address the coins of this key are paid to.
+    pub fn address(&self) -> String {
+        address(&self.public_key())
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        hex::encode(self.signing_key.sign(message.as_bytes()).to_bytes())
+    }
+}
+
+/// Derive the address of a public key, which is what transactions pay coins to.
+pub fn address(public_key: &str) -> String {
+    let mut hash = sha::get_double_sha256(public_key.to_string());
+    hash.truncate(ADDRESS_HEX_LEN);
+    hash
+}
+
+/// Check that `public_key` is a well-formed public key.
+pub fn is_public_key(public_key: &str) -> bool {
+    parse_public_key(public_key).is_some()
+}
+
+/// Check a signature made by `KeyPair::sign`.
+pub fn verify(public_key: &str, message: &str, signature: &str) -> bool {
+    let signature = match hex::decode(signature)
+        .ok()
+        .and_then(|x| Signature::from_slice(&x).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    match parse_public_key(public_key) {
+        Some(key) => key.verify_strict(message.as_bytes(), &signature).is_ok(),
+        None => false,
+    }
+}
+
+fn parse_public_key(public_key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(public_key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
@@ -1,24 +1,44 @@
 mod blockchain;
+mod consensus;
 mod core;
 mod crypt;
 mod p2p;
+mod storage;
 mod transaction;
 
+use anyhow::anyhow;
 use clap::{Parser, Subcommand};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
+use crate::consensus::SealerConfig;
 use crate::core::client::Client;
-use crate::core::server::{Overload, Server};
+use crate::core::clock::{Clock, SystemClock};
+use crate::core::server::{Overload, Server, ServerConfig};
+use crate::crypt::keys::KeyPair;
 use blockchain::block::Block;
 use blockchain::chain::Blockchain;
+use blockchain::export::ChainFormat;
+use blockchain::ledger::LedgerModel;
+use blockchain::spec::ChainSpec;
 use p2p::message::MsgType;
-use transaction::pool::{ToVecString, Transaction, TransactionPool};
+use storage::file::FileStore;
+use storage::memory::MemoryStore;
+use transaction::pool::{SignerVote, ToVecString, Transaction, TransactionPool};
+use transaction::utxo::{OutPoint, TxOutput, UtxoTransaction};
 
 const CHECK_INTERVAL: Duration = Duration::from_secs(10);
-static mut FLAG_STOP_BLOCK_BUILD: bool = false;
+/// Ports of the local demo nodes relative to the port of the genesis server of a network.
+const SERVER_PORT_OFFSET: u16 = 8;
+const FIRST_CLIENT_PORT_OFFSET: u16 = 13;
+const CLIENT_PORT_OFFSET: u16 = 6;
+/// Address the blocks mined by the blockchain demo pay their rewards to.
+const DEMO_MINER_ADDRESS: &str = "miner";
 
 #[derive(Parser)]
 #[clap(version, about, long_about = None)]
@@ -35,6 +55,34 @@ enum Commands {
         /// Launch a genesis server
         #[clap(long)]
         genesis: bool,
+
+        /// Persist the blockchain in a subdirectory of this directory named after the network
+        #[clap(long, value_name = "DIR")]
+        data_dir: Option<PathBuf>,
+
+        /// Join the network defined by this chain spec file
+        #[clap(long, value_name = "FILE")]
+        chain_spec: Option<PathBuf>,
+
+        /// Address to pay the block rewards to
+        #[clap(long, value_name = "ADDRESS")]
+        miner_address: String,
+
+        /// Sign blocks with this key file on a Proof of Authority network
+        #[clap(long, value_name = "FILE")]
+        signer_key: Option<PathBuf>,
+
+        /// Vote for adding this public key to the signers
+        #[clap(long, value_name = "PUBLIC_KEY")]
+        vote_add: Vec<String>,
+
+        /// Vote for removing this public key from the signers
+        #[clap(long, value_name = "PUBLIC_KEY")]
+        vote_remove: Vec<String>,
+
+        /// Vote on finality with this key file if it belongs to a validator
+        #[clap(long, value_name = "FILE")]
+        validator_key: Option<PathBuf>,
     },
 
     /// Launch a client
@@ -42,10 +90,74 @@ enum Commands {
         /// Launch the first client
         #[clap(long)]
         first: bool,
+
+        /// Join the network defined by this chain spec file
+        #[clap(long, value_name = "FILE")]
+        chain_spec: Option<PathBuf>,
     },
 
     /// Start a blockchain
     Blockchain,
+
+    /// Generate a signer key of a Proof of Authority network
+    GenerateKey {
+        /// File to write the secret key to
+        #[clap(long, short, value_name = "FILE")]
+        output: PathBuf,
+    },
+
+    /// Export the blockchain stored in a data directory
+    ExportChain {
+        /// Data directory the blockchain was persisted in by a server
+        #[clap(long, value_name = "DIR")]
+        data_dir: PathBuf,
+
+        /// Chain spec file of the blockchain
+        #[clap(long, value_name = "FILE")]
+        chain_spec: Option<PathBuf>,
+
+        /// File to write the blockchain to
+        #[clap(long, short, value_name = "FILE")]
+        output: PathBuf,
+
+        #[clap(long, value_enum, default_value_t = ChainFormat::Jsonl)]
+        format: ChainFormat,
+    },
+
+    /// Import a blockchain into a data directory after validating it
+    ImportChain {
+        /// Data directory to import the blockchain into, as given to a server
+        #[clap(long, value_name = "DIR")]
+        data_dir: PathBuf,
+
+        /// Chain spec file of the blockchain
+        #[clap(long, value_name = "FILE")]
+        chain_spec: Option<PathBuf>,
+
+        /// File to read the blockchain from
+        #[clap(long, short, value_name = "FILE")]
+        input: PathBuf,
+
+        #[clap(long, value_enum, default_value_t = ChainFormat::Jsonl)]
+        format: ChainFormat,
+    },
+
+    /// Show the balance of an address in a persisted blockchain
+    Balance {
+        /// Data directory the blockchain was persisted in by a server
+        #[clap(long, value_name = "DIR")]
+        data_dir: PathBuf,
+
+        /// Chain spec file of the blockchain
+        #[clap(long, value_name = "FILE")]
+        chain_spec: Option<PathBuf>,
+
+        /// Height to show the balance at, instead of the tip
+        #[clap(long)]
+        height: Option<usize>,
+
+        address: String,
+    },
 }
 
 fn wait_for_ctlc() {
@@ -59,123 +171,351 @@ fn wait_for_ctlc() {
     println!("Interrupted by user. Exiting ...");
 }
 
+fn run_export_chain(
+    data_dir: &Path,
+    spec: &ChainSpec,
+    output: &Path,
+    format: ChainFormat,
+) -> anyhow::Result<()> {
+    let storage = Arc::new(Mutex::new(FileStore::open(&spec.data_dir(data_dir))?));
+    let bc = Blockchain::new(
+        storage,
+        spec,
+        spec.consensus.build(SealerConfig::default())?,
+        Arc::new(SystemClock),
+    )?;
+    let chain = bc.get_chain();
+    blockchain::export::export_chain(&chain, format, BufWriter::new(File::create(output)?))?;
+    println!(
+        "{} blocks were exported to {}",
+        chain.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Spend the first genesis allocation of a UTXO chain, paying 3 coins to the second one
+/// and a fee of 1 to the miner.
+/// The first allocation must be paid to the demo key of alice, as in the dev chain spec.
+fn demo_utxo_transaction(spec: &ChainSpec) -> anyhow::Result<UtxoTransaction> {
+    let [from, to, ..] = spec.genesis.allocations.as_slice() else {
+        return Err(anyhow!("the UTXO demo needs two genesis allocations"));
+    };
+    // 割り当ては、ジェネシスブロックの先頭から並ぶcoinbaseトランザクションの出力になっている
+    let input = OutPoint {
+        txid: spec.network_id(),
+        index: 0,
+    };
+    let outputs = vec![
+        TxOutput {
+            owner: to.address.clone(),
+            value: 3,
+        },
+        TxOutput {
+            owner: from.address.clone(),
+            value: from.value - 3 - 1,
+        },
+    ];
+    Ok(UtxoTransaction::new_signed(
+        &KeyPair::from_seed("alice"),
+        vec![input],
+        outputs,
+        1,
+    ))
+}
+
+fn run_balance(
+    data_dir: &Path,
+    spec: &ChainSpec,
+    address: &str,
+    height: Option<usize>,
+) -> anyhow::Result<()> {
+    let storage = Arc::new(Mutex::new(FileStore::open(&spec.data_dir(data_dir))?));
+    let bc = Blockchain::new(
+        storage,
+        spec,
+        spec.consensus.build(SealerConfig::default())?,
+        Arc::new(SystemClock),
+    )?;
+    let height = height.unwrap_or_else(|| bc.get_height());
+    let block = bc
+        .get_block_by_height(height)
+        .ok_or_else(|| anyhow!("height {} is beyond the tip {}", height, bc.get_height()))?;
+    let balance = bc
+        .get_balance(address, Some(height))
+        .expect("height was checked to be on the chain");
+    println!(
+        "Balance of {} at height {} ({}) is ... {}",
+        address,
+        height,
+        bc.get_hash(&block),
+        balance
+    );
+    Ok(())
+}
+
+fn run_import_chain(
+    data_dir: &Path,
+    spec: &ChainSpec,
+    input: &Path,
+    format: ChainFormat,
+) -> anyhow::Result<()> {
+    let blocks = blockchain::export::import_chain(format, BufReader::new(File::open(input)?))?;
+    // 途中のブロックが不正でも手前までが保存されてしまわないよう、先にメモリ上で全体を検証する
+    let mut checked = Blockchain::new(
+        Arc::new(Mutex::new(MemoryStore::new())),
+        spec,
+        spec.consensus.build(SealerConfig::default())?,
+        Arc::new(SystemClock),
+    )?;
+    checked.merge_chain(blocks.clone())?;
+
+    let storage = Arc::new(Mutex::new(FileStore::open(&spec.data_dir(data_dir))?));
+    let mut bc = Blockchain::new(
+        storage,
+        spec,
+        spec.consensus.build(SealerConfig::default())?,
+        Arc::new(SystemClock),
+    )?;
+    bc.merge_chain(blocks)?;
+    println!(
+        "Blockchain in {} is now at height {}",
+        data_dir.display(),
+        bc.get_height()
+    );
+    Ok(())
+}
+
 fn generate_block_with_tp(
-    tp: Arc<Mutex<TransactionPool>>,
-    mut bc: Blockchain,
+    tp: Arc<Mutex<TransactionPool<MemoryStore>>>,
+    mut bc: Blockchain<MemoryStore>,
     mut prev_block_hash: String,
+    clock: Arc<dyn Clock>,
 ) {
-    let mut tp_guard = tp.lock().unwrap();
-    match tp_guard.get_stored_transactions() {
+    let consensus = bc.get_consensus();
+    if consensus.is_stopped() {
+        return;
+    }
+
+    let stored_transactions = tp
+        .lock()
+        .unwrap()
+        .get_block_candidates(|x| bc.get_next_nonce(x));
+    match stored_transactions {
         Some(result) => {
-            let result_len = result.len();
-
-            let new_block = Block::new(result.to_vec_string(), Some(prev_block_hash));
-            bc.set_new_block(new_block.clone());
-            prev_block_hash = bc.get_hash(&new_block);
-            // ブロック生成に成功したらTransaction Poolはクリアする
-            tp_guard.clear_my_transactions(result_len);
+            let mut new_block = bc.prepare_block(DEMO_MINER_ADDRESS, result);
+            if consensus.seal(&mut new_block) {
+                match bc.set_new_block(new_block.clone()) {
+                    Ok(()) => {
+                        prev_block_hash = bc.get_hash(&new_block);
+                        // ブロックに取り込まれたトランザクションをTransaction Poolから取り除く
+                        tp.lock().unwrap().remove_included(new_block.transactions());
+                    }
+                    Err(e) => eprintln!("Discard the mined block: {}", e),
+                };
+            };
         }
         None => println!("Transaction Pool is empty ..."),
     };
 
-    println!("Current Blockchain is ... {:#?}", bc.get_chain());
+    println!(
+        "Current Blockchain is ... {:#?}",
+        bc.get_headers(0..bc.get_height() + 1)
+    );
     println!("Current prev_block_hash is ... {}", prev_block_hash);
 
     let tp = tp.clone();
     thread::spawn(move || {
-        thread::sleep(CHECK_INTERVAL);
-        generate_block_with_tp(tp, bc, prev_block_hash);
+        clock.sleep(CHECK_INTERVAL);
+        generate_block_with_tp(tp, bc, prev_block_hash, clock);
     });
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Server { genesis } if *genesis => {
-            let mut my_p2p_server = Server::new(50082);
+        Commands::Server {
+            genesis,
+            data_dir,
+            chain_spec,
+            miner_address,
+            signer_key,
+            vote_add,
+            vote_remove,
+            validator_key,
+        } => {
+            let spec = ChainSpec::load_or_default(chain_spec.as_deref())?;
+            let port = spec.port;
+            let signer_key = signer_key.as_deref().map(KeyPair::load).transpose()?;
+            let votes = vote_add
+                .iter()
+                .map(|x| (x.clone(), SignerVote::Add))
+                .chain(vote_remove.iter().map(|x| (x.clone(), SignerVote::Remove)))
+                .collect();
+            let config = ServerConfig {
+                data_dir: data_dir.clone(),
+                chain_spec: spec,
+                sealer: SealerConfig { signer_key, votes },
+                validator_key: validator_key.as_deref().map(KeyPair::load).transpose()?,
+                ..ServerConfig::new(miner_address.clone())
+            };
+            let mut my_p2p_server = if *genesis {
+                Server::new((port, config))
+            } else {
+                let genesis_addr = format!("localhost:{}", port);
+                Server::new((port + SERVER_PORT_OFFSET, genesis_addr.as_str(), config))
+            };
             my_p2p_server.start();
+            if !*genesis {
+                my_p2p_server.join_network();
+            };
             wait_for_ctlc();
-        }
-        Commands::Server { .. } => {
-            let mut my_p2p_server = Server::new((50090, "localhost:50082"));
-            my_p2p_server.start();
-            my_p2p_server.join_network();
-            wait_for_ctlc();
+            my_p2p_server.shutdown();
         }
 
-        Commands::Client { first } if *first => {
-            let mut my_p2p_client = Client::new(50095, "localhost:50082");
+        Commands::Client { first, chain_spec } if *first => {
+            let spec = ChainSpec::load_or_default(chain_spec.as_deref())?;
+            let mut my_p2p_client = Client::new(
+                spec.port + FIRST_CLIENT_PORT_OFFSET,
+                &format!("localhost:{}", spec.port),
+                spec.network_id(),
+            );
             my_p2p_client.start();
 
             thread::sleep(Duration::from_secs(10));
 
-            let transaction = Transaction::new("test4", "test5", 3);
+            if spec.ledger == LedgerModel::Utxo {
+                my_p2p_client.send_utxo_transaction(demo_utxo_transaction(&spec)?);
+                wait_for_ctlc();
+                return Ok(());
+            };
+
+            let transaction =
+                Transaction::new_signed(&KeyPair::from_seed("alice"), "test5", 3, 1, 0);
             my_p2p_client.send_message_to_my_core_node(MsgType::NewTransaction, transaction);
 
-            let transaction2 = Transaction::new("test6", "test7", 2);
+            let transaction2 =
+                Transaction::new_signed(&KeyPair::from_seed("bob"), "test7", 2, 2, 0);
             my_p2p_client.send_message_to_my_core_node(MsgType::NewTransaction, transaction2);
 
             thread::sleep(Duration::from_secs(10));
 
-            let transaction3 = Transaction::new("test8", "test9", 10);
+            let transaction3 =
+                Transaction::new_signed(&KeyPair::from_seed("alice"), "test9", 10, 1, 1);
             my_p2p_client.send_message_to_my_core_node(MsgType::NewTransaction, transaction3);
 
             wait_for_ctlc();
         }
-        Commands::Client { .. } => {
-            let mut my_p2p_client = Client::new(50088, "localhost:50082");
+        Commands::Client { chain_spec, .. } => {
+            let spec = ChainSpec::load_or_default(chain_spec.as_deref())?;
+            let mut my_p2p_client = Client::new(
+                spec.port + CLIENT_PORT_OFFSET,
+                &format!("localhost:{}", spec.port),
+                spec.network_id(),
+            );
             my_p2p_client.start();
 
             thread::sleep(Duration::from_secs(10));
 
-            let transaction = Transaction::new("test1", "test2", 3);
-            my_p2p_client.send_message_to_my_core_node(MsgType::NewTransaction, transaction);
+            // 最初のクライアントが送るトランザクションに続くnonceを使う
+            let transaction =
+                Transaction::new_signed(&KeyPair::from_seed("alice"), "test2", 3, 1, 2);
+            my_p2p_client
+                .send_message_to_my_core_node(MsgType::NewTransaction, transaction.clone());
 
-            let transaction2 = Transaction::new("test1", "test3", 2);
+            let transaction2 =
+                Transaction::new_signed(&KeyPair::from_seed("alice"), "test3", 2, 1, 3);
             my_p2p_client.send_message_to_my_core_node(MsgType::NewTransaction, transaction2);
 
             thread::sleep(Duration::from_secs(10));
 
-            let transaction3 = Transaction::new("test5", "test6", 10);
+            let transaction3 =
+                Transaction::new_signed(&KeyPair::from_seed("bob"), "test6", 10, 2, 1);
             my_p2p_client.send_message_to_my_core_node(MsgType::NewTransaction, transaction3);
 
+            thread::sleep(Duration::from_secs(20));
+
+            my_p2p_client.request_merkle_proof(transaction, None);
+
             wait_for_ctlc();
         }
 
         Commands::Blockchain => {
-            let my_genesis_block = Block::new_genesis();
-            let bc = Blockchain::new(my_genesis_block.clone());
-            let tp = Arc::new(Mutex::new(TransactionPool::new()));
-
-            let prev_block_hash = bc.get_hash(&my_genesis_block);
+            let storage = Arc::new(Mutex::new(MemoryStore::new()));
+            let spec = ChainSpec::default();
+            let consensus = spec.consensus.build(SealerConfig::default())?;
+            let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+            let bc = Blockchain::new(storage.clone(), &spec, consensus, clock.clone()).unwrap();
+            let tp = Arc::new(Mutex::new(TransactionPool::new(storage)));
+            let consensus = bc.get_consensus();
+
+            let prev_block_hash = bc.get_last_block_hash();
             println!("genesis_block_hash : {}", prev_block_hash);
 
-            let transaction = Transaction::new("test1", "test2", 3);
+            let transaction =
+                Transaction::new_signed(&KeyPair::from_seed("alice"), "test2", 3, 1, 0);
             tp.lock().unwrap().set_new_transaction(transaction);
 
-            let transaction2 = Transaction::new("test1", "test3", 2);
+            let transaction2 =
+                Transaction::new_signed(&KeyPair::from_seed("alice"), "test3", 2, 1, 1);
             tp.lock().unwrap().set_new_transaction(transaction2);
 
             println!("Thread for generate_block_with_tp started!");
             {
                 let tp = tp.clone();
+                let clock = clock.clone();
                 thread::spawn(move || {
-                    thread::sleep(CHECK_INTERVAL);
-                    generate_block_with_tp(tp, bc, prev_block_hash);
+                    clock.sleep(CHECK_INTERVAL);
+                    generate_block_with_tp(tp, bc, prev_block_hash, clock);
                 });
             }
-            thread::sleep(Duration::from_secs(20));
+            clock.sleep(Duration::from_secs(20));
 
-            let transaction3 = Transaction::new("test5", "test6", 10);
+            let transaction3 =
+                Transaction::new_signed(&KeyPair::from_seed("bob"), "test6", 10, 2, 0);
             tp.lock().unwrap().set_new_transaction(transaction3);
 
-            thread::sleep(Duration::from_secs(30));
+            clock.sleep(Duration::from_secs(30));
 
-            unsafe {
-                FLAG_STOP_BLOCK_BUILD = true;
-            }
+            consensus.stop();
             println!("Stop the Thread for generate_block_with_tp");
         }
+
+        Commands::GenerateKey { output } => {
+            let key = KeyPair::generate();
+            key.save(output)?;
+            println!("Public key: {}", key.public_key());
+            println!("Address: {}", key.address());
+        }
+
+        Commands::ExportChain {
+            data_dir,
+            chain_spec,
+            output,
+            format,
+        } => {
+            let spec = ChainSpec::load_or_default(chain_spec.as_deref())?;
+            run_export_chain(data_dir, &spec, output, *format)?
+        }
+        Commands::ImportChain {
+            data_dir,
+            chain_spec,
+            input,
+            format,
+        } => {
+            let spec = ChainSpec::load_or_default(chain_spec.as_deref())?;
+            run_import_chain(data_dir, &spec, input, *format)?
+        }
+        Commands::Balance {
+            data_dir,
+            chain_spec,
+            height,
+            address,
+        } => {
+            let spec = ChainSpec::load_or_default(chain_spec.as_deref())?;
+            run_balance(data_dir, &spec, address, *height)?
+        }
     };
+    Ok(())
 }
@@ -1,20 +1,42 @@
+use anyhow::anyhow;
 use std::collections::HashSet;
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::str::from_utf8;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
+use crate::blockchain::ledger::LedgerModel;
+use crate::blockchain::limits::MAX_BLOCK_SIZE;
+use crate::blockchain::merkle::InclusionProof;
+use crate::blockchain::validation::InvalidBlock;
+use crate::consensus::finality::{Checkpoint, Finality, FinalityVote, Phase};
+use crate::core::clock::Clock;
 use crate::p2p::message;
 use crate::p2p::node_list::{CoreNodeList, EdgeNodeList, NodeList};
 use crate::p2p::protocol_handler::ProtocolHandler;
-use crate::{MsgType, Transaction, TransactionPool};
+use crate::storage::NodeStorage;
+use crate::transaction::pool::MIN_RELAY_FEE;
+use crate::transaction::utxo::UtxoTransaction;
+use crate::{Block, Blockchain, MsgType, Transaction, TransactionPool};
 
 const PING_INTERVAL: Duration = Duration::from_secs(10);
-
-fn u8_to_str(content: &[u8]) -> String {
-    from_utf8(content).unwrap().to_string()
+/// Largest message read from a peer. A block takes at most twice its size once escaped
+/// in a payload, and a full chain of this many full blocks still fits.
+const MAX_MESSAGE_SIZE: u64 = 2 * MAX_BLOCK_SIZE as u64 * 256;
+
+/// Read a whole message, which the peer ends by closing the connection.
+fn read_message(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut b = Vec::new();
+    // 上限を1バイトでも超えれば、それ以上は読まずに捨てる
+    stream.take(MAX_MESSAGE_SIZE + 1).read_to_end(&mut b)?;
+    if b.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(anyhow!(
+            "message exceeds the limit of {} bytes",
+            MAX_MESSAGE_SIZE
+        ));
+    }
+    Ok(String::from_utf8(b)?)
 }
 
 #[allow(drop_bounds)]
@@ -24,16 +46,25 @@ pub trait Manager: Drop + Clone {
     where
         Self: 'static + Send,
     {
-        let mut self_clone = self.clone();
         {
             // Reference: https://stackoverflow.com/a/33455247
-            let self_clone = self_clone.clone();
+            let self_clone = self.clone();
             thread::spawn(move || {
                 self_clone.wait_for_access(my_addr);
             });
         }
+        self.schedule_ping();
+    }
+
+    /// Send a ping once PING_INTERVAL has passed on the clock.
+    /// `send_ping` calls this again to keep pinging.
+    fn schedule_ping(&self)
+    where
+        Self: 'static + Send,
+    {
+        let mut self_clone = self.clone();
         thread::spawn(move || {
-            thread::sleep(PING_INTERVAL);
+            self_clone.get_clock().sleep(PING_INTERVAL);
             self_clone.send_ping();
         });
     }
@@ -41,7 +72,7 @@ pub trait Manager: Drop + Clone {
     /// 指定したCoreノードへ接続要求メッセージを送信する
     fn connect_to_p2pnw(&self, my_addr: SocketAddr, node_addr: SocketAddr, msg_type: MsgType) {
         let mut stream = TcpStream::connect(node_addr).unwrap();
-        let msg = message::build(msg_type, my_addr, None, None);
+        let msg = self.build_message(msg_type, my_addr, None, None);
         thread::spawn(move || {
             stream.write_all(msg.as_bytes()).unwrap();
         });
@@ -56,11 +87,20 @@ pub trait Manager: Drop + Clone {
         for stream in listener.incoming() {
             match stream {
                 Ok(mut stream) => {
+                    let peer = match stream.peer_addr() {
+                        Ok(peer) => peer,
+                        Err(e) => {
+                            eprintln!("An error occurred while accepting a connection: {}", e);
+                            continue;
+                        }
+                    };
                     let mut self_clone = self.clone();
                     thread::spawn(move || {
-                        let mut b = [0; 1024];
-                        let n = stream.read(&mut b).unwrap();
-                        self_clone.handle_message(&u8_to_str(&b[0..n]));
+                        // ブロックやMerkle proofは1024バイトに収まらないので、送信側が閉じるまで読む
+                        match read_message(&mut stream) {
+                            Ok(data) => self_clone.handle_message(peer, &data),
+                            Err(e) => eprintln!("Dropped a message from ({}): {:#}", peer, e),
+                        };
                     });
                 }
                 Err(e) => {
@@ -78,12 +118,41 @@ pub trait Manager: Drop + Clone {
         new_core_set: Option<HashSet<SocketAddr>>,
         new_transaction: Option<Transaction>,
     ) -> String {
-        message::build(msg_type, my_addr, new_core_set, new_transaction)
+        message::build(
+            self.get_network_id(),
+            msg_type,
+            my_addr,
+            self.get_clock().now(),
+            new_core_set,
+            new_transaction,
+        )
     }
 
-    fn handle_message(&mut self, data: &str);
+    fn build_message_with_payload(
+        &self,
+        msg_type: MsgType,
+        my_addr: SocketAddr,
+        new_transaction: Option<Transaction>,
+        payload: Option<String>,
+    ) -> String {
+        message::build_with_payload(
+            self.get_network_id(),
+            msg_type,
+            my_addr,
+            self.get_clock().now(),
+            new_transaction,
+            payload,
+        )
+    }
+
+    /// Process a message received over a connection from `peer`.
+    fn handle_message(&mut self, peer: SocketAddr, data: &str);
     fn send_msg(&mut self, peer: &SocketAddr, msg: String);
     fn send_ping(&mut self);
+    /// Clock which paces the pings and stamps the messages.
+    fn get_clock(&self) -> Arc<dyn Clock>;
+    /// Network this node belongs to. Messages from other networks are rejected.
+    fn get_network_id(&self) -> &str;
 }
 
 /// For ServerCore
@@ -94,11 +163,22 @@ pub struct ConnectionManager {
     core_node_set: Arc<Mutex<CoreNodeList>>,
     edge_node_set: Arc<Mutex<EdgeNodeList>>,
     ph: ProtocolHandler,
-    pub tp: Arc<Mutex<TransactionPool>>,
+    pub tp: Arc<Mutex<TransactionPool<NodeStorage>>>,
+    bc: Blockchain<NodeStorage>,
+    clock: Arc<dyn Clock>,
+    network_id: String,
+    /// Votes of the BFT finality layer, if the chain spec enables it.
+    finality: Option<Arc<Mutex<Finality>>>,
 }
 
 impl ConnectionManager {
-    pub fn new(self_addr: SocketAddr) -> ConnectionManager {
+    pub fn new(
+        self_addr: SocketAddr,
+        bc: Blockchain<NodeStorage>,
+        tp: TransactionPool<NodeStorage>,
+        clock: Arc<dyn Clock>,
+        finality: Option<Finality>,
+    ) -> ConnectionManager {
         println!("Initializing ConnectionManager ...");
         let mut core_node_list = CoreNodeList::new();
         core_node_list.add(self_addr);
@@ -108,7 +188,11 @@ impl ConnectionManager {
             core_node_set: Arc::new(Mutex::new(core_node_list)),
             edge_node_set: Arc::new(Mutex::new(EdgeNodeList::new())),
             ph: ProtocolHandler::new(),
-            tp: Arc::new(Mutex::new(TransactionPool::new())),
+            tp: Arc::new(Mutex::new(tp)),
+            network_id: bc.get_genesis_hash(),
+            bc,
+            clock,
+            finality: finality.map(|x| Arc::new(Mutex::new(x))),
         }
     }
 
@@ -118,11 +202,123 @@ impl ConnectionManager {
         for peer in list {
             if peer != self.addr {
                 println!("message will be sent to ... ({})", peer);
+                self.send_msg(&peer, msg.clone());
             };
-            self.send_msg(&peer, msg.clone());
         }
     }
 
+    /// Send a block mined by this node to all other core nodes.
+    pub fn broadcast_new_block(&mut self, block: &Block) {
+        let msg = self.build_message_with_payload(
+            MsgType::NewBlock,
+            self.addr,
+            None,
+            Some(serde_json::to_string(block).unwrap()),
+        );
+        self.send_msg_to_all_peer(msg);
+    }
+
+    /// Vote for a block which joined the active chain, and act on the votes of
+    /// other validators which arrived before it. (BFT finality)
+    pub fn on_block_connected(&mut self, block_hash: &str) {
+        if self.finality.is_none() {
+            return;
+        }
+        self.send_vote(Phase::Prepare, block_hash);
+        self.advance_finality(block_hash);
+    }
+
+    /// Sign our vote on a block of the active chain, count it and send it to all core nodes.
+    fn send_vote(&mut self, phase: Phase, block_hash: &str) {
+        let finality = match &self.finality {
+            Some(finality) => finality.clone(),
+            None => return,
+        };
+        let height = match self.bc.get_height_of(block_hash) {
+            Some(height) => height,
+            None => return,
+        };
+        let vote = match finality.lock().unwrap().sign(phase, block_hash, height) {
+            Some(vote) => vote,
+            None => return,
+        };
+        finality.lock().unwrap().add_vote(phase, &vote);
+        let msg_type = match phase {
+            Phase::Prepare => MsgType::Prepare,
+            Phase::Commit => MsgType::Commit,
+        };
+        let msg = self.build_message_with_payload(
+            msg_type,
+            self.addr,
+            None,
+            Some(serde_json::to_string(&vote).unwrap()),
+        );
+        self.send_msg_to_all_peer(msg);
+    }
+
+    /// Count a vote received from another validator.
+    fn receive_vote(&mut self, phase: Phase, vote: FinalityVote) {
+        let finality = match &self.finality {
+            Some(finality) => finality.clone(),
+            None => return,
+        };
+        // 確定済みの高さへの投票は、もう結果を変えないので数えない
+        if vote.height <= self.bc.get_finalized().height {
+            return;
+        }
+        {
+            let mut finality = finality.lock().unwrap();
+            if !finality.is_valid(phase, &vote) {
+                eprintln!("rejected {:?} vote: {:#?}", phase, vote);
+                return;
+            }
+            finality.add_vote(phase, &vote);
+        }
+        self.advance_finality(&vote.block_hash);
+    }
+
+    /// Commit a block once a quorum prepared it, and finalize it once a quorum committed it.
+    fn advance_finality(&mut self, block_hash: &str) {
+        let finality = match &self.finality {
+            Some(finality) => finality.clone(),
+            None => return,
+        };
+        // アクティブチェーンにないブロックは、接続されたときにon_block_connectedで再び確認する
+        if self.bc.get_height_of(block_hash).is_none() {
+            return;
+        }
+        let prepared = finality.lock().unwrap().reach(Phase::Prepare, block_hash);
+        if prepared {
+            self.send_vote(Phase::Commit, block_hash);
+        }
+        let committed = finality.lock().unwrap().reach(Phase::Commit, block_hash);
+        if !committed {
+            return;
+        }
+        match self.bc.finalize(block_hash) {
+            Ok(height) => {
+                println!("Block was finalized at height {}: {}", height, block_hash);
+                finality.lock().unwrap().prune(height);
+                let list = self.edge_node_set.lock().unwrap().get_list();
+                for edge in list {
+                    self.send_finalized(&edge);
+                }
+            }
+            Err(e) => eprintln!("Failed to finalize the block: {}", e),
+        };
+    }
+
+    /// Tell an edge node the last finalized block.
+    fn send_finalized(&mut self, edge: &SocketAddr) {
+        let msg = self.build_message_with_payload(
+            MsgType::Finalized,
+            self.addr,
+            None,
+            Some(serde_json::to_string(&self.bc.get_finalized()).unwrap()),
+        );
+        self.send_msg(edge, msg);
+    }
+
     /// Add a core node to the list.
     fn add_peer(&mut self, peer: &SocketAddr) {
         self.core_node_set.lock().unwrap().add(*peer);
@@ -152,13 +348,15 @@ impl ConnectionManager {
     pub fn join_network(&mut self, node_addr: SocketAddr) {
         self.my_c_addr = Some(node_addr);
         self.connect_to_p2pnw(self.addr, node_addr, MsgType::Add);
+        // 参加前に生成されたブロックを受け取る
+        self.connect_to_p2pnw(self.addr, node_addr, MsgType::RequestFullChain);
     }
 
     /// Send a message to confirm valid nodes.
     fn is_alive(&self, target: &SocketAddr) -> bool {
         match TcpStream::connect(target) {
             Ok(mut stream) => {
-                let msg = message::build(MsgType::Ping, self.addr, None, None);
+                let msg = self.build_message(MsgType::Ping, self.addr, None, None);
                 let result = thread::spawn(move || stream.write(msg.as_bytes()));
                 result.join().is_ok()
             }
@@ -168,10 +366,19 @@ impl ConnectionManager {
 }
 
 impl Manager for ConnectionManager {
-    fn handle_message(&mut self, data: &str) {
-        match message::parse(data) {
+    fn handle_message(&mut self, peer: SocketAddr, data: &str) {
+        match message::parse(data, self.get_network_id()) {
             Ok(msg) => {
                 println!("Connected by .. ({})", msg.my_addr);
+                // 時刻は、接続元と名乗るアドレスが一致するCoreノードのものだけを標本にする
+                if msg.my_addr != self.addr
+                    && msg.my_addr.ip() == peer.ip()
+                    && self.is_in_core_set(&msg.my_addr)
+                {
+                    self.bc
+                        .get_network_time()
+                        .add_sample(peer.ip(), msg.timestamp);
+                };
                 match msg.msg_type {
                     MsgType::Add => {
                         println!("ADD node request was received!!");
@@ -221,6 +428,9 @@ impl Manager for ConnectionManager {
                             None,
                         );
                         self.send_msg(&msg.my_addr, m);
+                        if self.finality.is_some() {
+                            self.send_finalized(&msg.my_addr);
+                        };
                     }
                     MsgType::RemoveEdge => {
                         println!("REMOVE_EDGE request was received!! from: ({})", msg.my_addr);
@@ -238,36 +448,170 @@ impl Manager for ConnectionManager {
                         let new_transaction = msg.new_transaction.unwrap();
                         println!("received new_transaction: {:#?}", new_transaction);
 
-                        if let Some(current_transactions) =
-                            self.tp.lock().unwrap().get_stored_transactions()
-                        {
-                            if current_transactions.contains(&new_transaction) {
-                                println!(
-                                    "this is already pooled transaction: {:#?}",
-                                    new_transaction
-                                );
-                                return;
-                            };
+                        if !new_transaction.is_well_formed() {
+                            eprintln!("this transaction is malformed: {:#?}", new_transaction);
+                            return;
+                        };
+                        if !new_transaction.has_valid_signature() {
+                            eprintln!(
+                                "this transaction has an invalid signature: {:#?}",
+                                new_transaction
+                            );
+                            return;
+                        };
+                        if self.bc.get_ledger_model() != LedgerModel::Account {
+                            eprintln!("transfers between accounts are not used on this chain");
+                            return;
+                        };
+                        if new_transaction.get_fee() < MIN_RELAY_FEE {
+                            eprintln!(
+                                "this transaction pays a fee of {}, below the minimum relay fee of {}",
+                                new_transaction.get_fee(),
+                                MIN_RELAY_FEE
+                            );
+                            return;
+                        };
+
+                        let sender = new_transaction.get_sender();
+                        let next_nonce = self.bc.get_next_nonce(sender);
+                        let balance = self.bc.get_balance(sender, None).unwrap_or(0);
+                        let mut tp = self.tp.lock().unwrap();
+                        if let Err(e) = tp.check_admission(&new_transaction, next_nonce, balance) {
+                            eprintln!("rejected new_transaction: {}", e);
+                            return;
                         };
 
+                        // nonceが先の方のトランザクションも、間が埋まるまでプールで待たせる
+                        if !tp.set_new_transaction(new_transaction.clone()) {
+                            println!("this is already pooled transaction: {:#?}", new_transaction);
+                            return;
+                        };
+                        drop(tp);
+
                         if !self.is_in_core_set(&msg.my_addr) {
-                            self.tp
-                                .lock()
-                                .unwrap()
-                                .set_new_transaction(new_transaction.clone());
                             let new_message = self.build_message(
-                                MsgType::NewBlock,
+                                MsgType::NewTransaction,
                                 self.addr,
                                 None,
                                 Some(new_transaction),
                             );
                             self.send_msg_to_all_peer(new_message);
-                        } else {
-                            self.tp.lock().unwrap().set_new_transaction(new_transaction);
                         };
                     }
-                    MsgType::NewBlock => {} // TODO: 新規ブロックを検証する処理
-                    MsgType::RspFullChain => {} // TODO: ブロックチェーン送信要求に応じて返却されたブロックチェーンを検証する処理
+                    MsgType::NewUtxoTransaction => {
+                        let new_transaction: UtxoTransaction = match msg.decode_payload() {
+                            Ok(txn) => txn,
+                            Err(e) => {
+                                eprintln!("rejected new_utxo_transaction: {:#}", e);
+                                return;
+                            }
+                        };
+                        println!("received new_utxo_transaction: {:#?}", new_transaction);
+
+                        if !new_transaction.is_well_formed() {
+                            eprintln!("this transaction is malformed: {:#?}", new_transaction);
+                            return;
+                        };
+                        if new_transaction.get_fee() < MIN_RELAY_FEE {
+                            eprintln!(
+                                "this transaction pays a fee of {}, below the minimum relay fee of {}",
+                                new_transaction.get_fee(),
+                                MIN_RELAY_FEE
+                            );
+                            return;
+                        };
+                        if let Err(e) = self.bc.check_utxo_transaction(&new_transaction) {
+                            eprintln!("rejected new_utxo_transaction: {}", e);
+                            return;
+                        };
+                        // 既にプールにあるものも、入力が重なるのでここで弾かれる
+                        if !self
+                            .tp
+                            .lock()
+                            .unwrap()
+                            .set_new_utxo_transaction(new_transaction.clone())
+                        {
+                            println!("a pooled transaction already spends these inputs ...");
+                            return;
+                        };
+
+                        if !self.is_in_core_set(&msg.my_addr) {
+                            let new_message = self.build_message_with_payload(
+                                MsgType::NewUtxoTransaction,
+                                self.addr,
+                                None,
+                                Some(new_transaction.to_string()),
+                            );
+                            self.send_msg_to_all_peer(new_message);
+                        };
+                    }
+                    MsgType::RequestMerkleProof => {
+                        let transaction = msg.new_transaction.unwrap();
+                        println!("Merkle proof was requested for: {:#?}", transaction);
+                        // ブロックのハッシュ値が指定されていなければチェーン全体から探す
+                        let proof = match msg.payload {
+                            Some(block_hash) => self
+                                .bc
+                                .get_inclusion_proof(&block_hash, &transaction.to_string()),
+                            None => self.bc.find_inclusion_proof(&transaction.to_string()),
+                        };
+                        if proof.is_none() {
+                            println!("this transaction is not included in any block yet ...");
+                        };
+                        let m = self.build_message_with_payload(
+                            MsgType::RspMerkleProof,
+                            self.addr,
+                            Some(transaction),
+                            proof.map(|x| serde_json::to_string(&x).unwrap()),
+                        );
+                        self.send_msg(&msg.my_addr, m);
+                    }
+                    MsgType::NewBlock => {
+                        let new_block: Block = match msg.decode_payload() {
+                            Ok(block) => block,
+                            Err(e) => {
+                                eprintln!("rejected new_block: {:#}", e);
+                                return;
+                            }
+                        };
+                        println!("received new_block: {:#?}", new_block);
+                        if let Err(e) = self.bc.set_new_block(new_block) {
+                            eprintln!("rejected new_block: {}", e);
+                            if e.reason == InvalidBlock::UnknownParent {
+                                // 自分のチェーンが遅れている可能性があるので、全体を要求する
+                                let m = self.build_message(
+                                    MsgType::RequestFullChain,
+                                    self.addr,
+                                    None,
+                                    None,
+                                );
+                                self.send_msg(&msg.my_addr, m);
+                            };
+                        };
+                    }
+                    MsgType::RequestFullChain => {
+                        println!("Full chain was requested by: ({})", msg.my_addr);
+                        let m = self.build_message_with_payload(
+                            MsgType::RspFullChain,
+                            self.addr,
+                            None,
+                            Some(serde_json::to_string(&self.bc.get_chain()).unwrap()),
+                        );
+                        self.send_msg(&msg.my_addr, m);
+                    }
+                    MsgType::RspFullChain => {
+                        let new_chain: Vec<Block> = match msg.decode_payload() {
+                            Ok(chain) => chain,
+                            Err(e) => {
+                                eprintln!("rejected full chain: {:#}", e);
+                                return;
+                            }
+                        };
+                        // 取り込んだ結果チェーンが切り替われば、ChainEventとしてServerに通知される
+                        if let Err(e) = self.bc.merge_chain(new_chain) {
+                            eprintln!("rejected full chain: {}", e);
+                        };
+                    }
                     MsgType::Enhanced => {
                         // P2P Network を単なるトランスポートして使っているアプリケーションが独自拡張したメッセージはここで処理する。
                         // SimpleBitcoin としてはこの種別は使わない
@@ -275,6 +619,20 @@ impl Manager for ConnectionManager {
                         println!("received enhanced message: {:?}", msg);
                         self.ph.handle_message(msg);
                     }
+                    MsgType::Prepare | MsgType::Commit => {
+                        let phase = match msg.msg_type {
+                            MsgType::Prepare => Phase::Prepare,
+                            _ => Phase::Commit,
+                        };
+                        let vote: FinalityVote = match msg.decode_payload() {
+                            Ok(vote) => vote,
+                            Err(e) => {
+                                eprintln!("rejected finality vote: {:#}", e);
+                                return;
+                            }
+                        };
+                        self.receive_vote(phase, vote);
+                    }
                     _ => {}
                 };
             }
@@ -319,11 +677,15 @@ impl Manager for ConnectionManager {
             self.send_msg_to_all_peer(msg);
         }
 
-        let mut self_clone = self.clone();
-        thread::spawn(move || {
-            thread::sleep(PING_INTERVAL);
-            self_clone.send_ping();
-        });
+        self.schedule_ping();
+    }
+
+    fn get_clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn get_network_id(&self) -> &str {
+        &self.network_id
     }
 }
 
@@ -336,7 +698,7 @@ impl Drop for ConnectionManager {
         match self.my_c_addr {
             None => {}
             Some(my_c_addr) => {
-                let msg = message::build(MsgType::Remove, self.addr, None, None);
+                let msg = self.build_message(MsgType::Remove, self.addr, None, None);
                 self.send_msg(&my_c_addr, msg);
             }
         };
@@ -349,18 +711,62 @@ pub struct ConnectionManager4Edge {
     pub addr: SocketAddr, // FIXME:
     my_core_addr: SocketAddr,
     core_node_set: Arc<Mutex<CoreNodeList>>,
+    clock: Arc<dyn Clock>,
+    network_id: String,
 }
 
 impl Manager for ConnectionManager4Edge {
     /// Process according to the received message.
-    fn handle_message(&mut self, data: &str) {
-        match message::parse(data) {
+    fn handle_message(&mut self, _peer: SocketAddr, data: &str) {
+        match message::parse(data, self.get_network_id()) {
             Ok(msg) => {
                 println!("Connected by .. ({})", msg.my_addr);
                 match msg.new_core_set {
                     None => {
                         match msg.msg_type {
                             MsgType::Ping => {}
+                            MsgType::Finalized => {
+                                let checkpoint: Checkpoint = match msg.decode_payload() {
+                                    Ok(checkpoint) => checkpoint,
+                                    Err(e) => {
+                                        eprintln!("rejected finalized checkpoint: {:#}", e);
+                                        return;
+                                    }
+                                };
+                                println!(
+                                    "Finalized height is ... {} ({})",
+                                    checkpoint.height, checkpoint.block_hash
+                                );
+                            }
+                            MsgType::RspMerkleProof => {
+                                let transaction = msg.new_transaction.unwrap();
+                                match msg.payload {
+                                    Some(payload) => {
+                                        let proof: InclusionProof =
+                                            serde_json::from_str(&payload).unwrap();
+                                        if proof.header.hash() == proof.block_hash
+                                            && proof.header.verify_inclusion(
+                                                &transaction.to_string(),
+                                                &proof.proof,
+                                            )
+                                        {
+                                            println!(
+                                                "transaction is included in block: {}",
+                                                proof.block_hash
+                                            );
+                                        } else {
+                                            eprintln!(
+                                                "received an invalid Merkle proof: {:#?}",
+                                                proof
+                                            );
+                                        };
+                                    }
+                                    None => println!(
+                                        "transaction is not included in any block yet: {:#?}",
+                                        transaction
+                                    ),
+                                };
+                            }
                             _ => {
                                 // 接続情報以外のメッセージしかEdgeノードで処理することは想定していない
                                 println!("Edge node does not have functions for this message!");
@@ -399,7 +805,7 @@ impl Manager for ConnectionManager4Edge {
     }
 
     fn send_ping(&mut self) {
-        let msg = message::build(MsgType::Ping, self.addr, None, None);
+        let msg = self.build_message(MsgType::Ping, self.addr, None, None);
         let my_core_addr = self.my_core_addr;
         match self.send(&my_core_addr, msg) {
             Ok(_) => {}
@@ -407,21 +813,32 @@ impl Manager for ConnectionManager4Edge {
             Err(Err(_)) => return,
         };
 
-        let mut self_clone = self.clone();
-        thread::spawn(move || {
-            thread::sleep(PING_INTERVAL);
-            self_clone.send_ping();
-        });
+        self.schedule_ping();
+    }
+
+    fn get_clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn get_network_id(&self) -> &str {
+        &self.network_id
     }
 }
 
 impl ConnectionManager4Edge {
-    pub fn new(self_addr: SocketAddr, my_core_addr: SocketAddr) -> ConnectionManager4Edge {
+    pub fn new(
+        self_addr: SocketAddr,
+        my_core_addr: SocketAddr,
+        network_id: String,
+        clock: Arc<dyn Clock>,
+    ) -> ConnectionManager4Edge {
         println!("Initializing ConnectionManager4Edge ...");
         ConnectionManager4Edge {
             addr: self_addr,
             my_core_addr,
             core_node_set: Arc::new(Mutex::new(CoreNodeList::new())),
+            clock,
+            network_id,
         }
     }
 
@@ -462,3 +879,72 @@ impl Drop for ConnectionManager4Edge {
         println!("Finishing ConnectionManager4Edge ...");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::consensus::SealerConfig;
+    use crate::core::clock::MockClock;
+    use crate::storage::memory::MemoryStore;
+
+    fn new_cm(addr: SocketAddr, clock: Arc<MockClock>) -> ConnectionManager {
+        let storage: NodeStorage = Box::new(MemoryStore::new());
+        let storage = Arc::new(Mutex::new(storage));
+        let spec = ChainSpec::default();
+        let consensus = spec.consensus.build(SealerConfig::default()).unwrap();
+        let bc = Blockchain::new(storage.clone(), &spec, consensus, clock.clone()).unwrap();
+        ConnectionManager::new(addr, bc, TransactionPool::new(storage), clock, None)
+    }
+
+    /// Send `data` over a loopback connection and read it back as a message.
+    fn send_and_read(data: Vec<u8>) -> anyhow::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // 読み手が上限で読むのをやめると、書き込みは失敗することがある
+            let _ = stream.write_all(&data);
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let result = read_message(&mut stream);
+        drop(stream);
+        sender.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn message_is_read_until_the_peer_closes() {
+        assert_eq!(send_and_read(b"hello".to_vec()).unwrap(), "hello");
+    }
+
+    #[test]
+    fn oversized_or_invalid_message_is_dropped() {
+        let oversized = vec![b'a'; MAX_MESSAGE_SIZE as usize + 1];
+        assert!(send_and_read(oversized).is_err());
+        assert!(send_and_read(vec![0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn dead_peer_is_dropped_once_the_ping_interval_has_passed() {
+        let clock = Arc::new(MockClock::new(0));
+        // 自分のアドレスへの接続は受け付けるだけにして、生きていることにする
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let cm = new_cm(listener.local_addr().unwrap(), clock.clone());
+        cm.core_node_set.lock().unwrap().add(dead);
+
+        cm.schedule_ping();
+        clock.wait_for_sleeps(1);
+        clock.advance(PING_INTERVAL - Duration::from_secs(1));
+        assert!(cm.is_in_core_set(&dead));
+        clock.advance(Duration::from_secs(1));
+        // 次のpingの予定が入ったときには、今回の確認は済んでいる
+        clock.wait_for_sleeps(2);
+        assert!(!cm.is_in_core_set(&dead));
+        assert!(cm.is_in_core_set(&cm.addr));
+    }
+}
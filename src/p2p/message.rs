@@ -1,5 +1,6 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use semver::Version;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::net::SocketAddr;
@@ -23,55 +24,153 @@ pub enum MsgType {
     NewBlock,
     RequestFullChain,
     RspFullChain,
+    RequestMerkleProof,
+    RspMerkleProof,
     Enhanced,
+    /// Transaction of a UTXO ledger, carried in the payload.
+    NewUtxoTransaction,
+    /// Vote of a validator that it accepted a block. (BFT finality)
+    Prepare,
+    /// Vote of a validator that a quorum accepted a block. (BFT finality)
+    Commit,
+    /// Last finalized block, sent to edge nodes.
+    Finalized,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message {
     pub protocol: String,
     pub version: String,
+    /// Network of the sender, i.e. the hash of its genesis block.
+    pub network_id: String,
     pub msg_type: MsgType,
     pub my_addr: SocketAddr,
+    /// Clock of the sender, used to compute the network-adjusted time.
+    pub timestamp: i64,
     pub new_core_set: Option<HashSet<SocketAddr>>,
     pub new_transaction: Option<Transaction>,
+    /// JSON-encoded body for message types that need more than the fields above.
+    #[serde(default)]
+    pub payload: Option<String>,
 }
 
 impl Message {
     pub fn new(
+        network_id: &str,
         msg_type: MsgType,
         my_addr: SocketAddr,
+        timestamp: i64,
         new_core_set: Option<HashSet<SocketAddr>>,
         new_transaction: Option<Transaction>,
     ) -> Message {
         Message {
             protocol: PROTOCOL_NAME.to_string(),
             version: PROTOCOL_VERSION.to_string(),
+            network_id: network_id.to_string(),
             msg_type,
             my_addr,
+            timestamp,
             new_core_set,
             new_transaction,
+            payload: None,
         }
     }
+
+    /// Decode the JSON-encoded payload, which peers may have left out or malformed.
+    pub fn decode_payload<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        let payload = self
+            .payload
+            .as_deref()
+            .ok_or_else(|| anyhow!("{:?} message has no payload", self.msg_type))?;
+        serde_json::from_str(payload)
+            .with_context(|| format!("payload of {:?} message is malformed", self.msg_type))
+    }
 }
 
 pub fn build(
+    network_id: &str,
     msg_type: MsgType,
     my_addr: SocketAddr,
+    timestamp: i64,
     new_core_set: Option<HashSet<SocketAddr>>,
     new_transaction: Option<Transaction>,
 ) -> String {
-    let msg = Message::new(msg_type, my_addr, new_core_set, new_transaction);
+    let msg = Message::new(
+        network_id,
+        msg_type,
+        my_addr,
+        timestamp,
+        new_core_set,
+        new_transaction,
+    );
     serde_json::to_string(&msg).unwrap()
 }
 
-pub fn parse(msg_str: &str) -> anyhow::Result<Message> {
-    let msg: Message = serde_json::from_str(msg_str).unwrap();
+pub fn build_with_payload(
+    network_id: &str,
+    msg_type: MsgType,
+    my_addr: SocketAddr,
+    timestamp: i64,
+    new_transaction: Option<Transaction>,
+    payload: Option<String>,
+) -> String {
+    let mut msg = Message::new(
+        network_id,
+        msg_type,
+        my_addr,
+        timestamp,
+        None,
+        new_transaction,
+    );
+    msg.payload = payload;
+    serde_json::to_string(&msg).unwrap()
+}
+
+/// Parse a message, rejecting those from other protocols and networks.
+pub fn parse(msg_str: &str, network_id: &str) -> anyhow::Result<Message> {
+    let msg: Message = serde_json::from_str(msg_str).context("Message is malformed")?;
 
     if msg.protocol != PROTOCOL_NAME {
         Err(anyhow!("Protocol name is not matched"))
     } else if Version::parse(&msg.version)? > Version::parse(PROTOCOL_VERSION)? {
         Err(anyhow!("Protocol version is not matched"))
+    } else if msg.network_id != network_id {
+        Err(anyhow!(
+            "Network is not matched: {} from ({})",
+            msg.network_id,
+            msg.my_addr
+        ))
     } else {
         Ok(msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(payload: Option<&str>) -> Message {
+        let mut msg = Message::new(
+            "network",
+            MsgType::NewBlock,
+            "127.0.0.1:50082".parse().unwrap(),
+            0,
+            None,
+            None,
+        );
+        msg.payload = payload.map(|x| x.to_string());
+        msg
+    }
+
+    #[test]
+    fn missing_or_malformed_payload_is_an_error() {
+        assert!(message(None).decode_payload::<Vec<i32>>().is_err());
+        assert!(message(Some("[1, 2")).decode_payload::<Vec<i32>>().is_err());
+        assert_eq!(
+            message(Some("[1, 2]"))
+                .decode_payload::<Vec<i32>>()
+                .unwrap(),
+            vec![1, 2]
+        );
+    }
+}
@@ -54,7 +54,7 @@ impl NodeList for CoreNodeList {
 
     fn get_top_peer(&self) -> SocketAddr {
         let mut vec = Vec::new();
-        vec.extend(self.list.clone().into_iter());
+        vec.extend(self.list.clone());
         vec[0]
     }
 }
@@ -115,7 +115,7 @@ impl NodeList for EdgeNodeList {
     #[allow(dead_code)]
     fn get_top_peer(&self) -> SocketAddr {
         let mut vec = Vec::new();
-        vec.extend(self.list.clone().into_iter());
+        vec.extend(self.list.clone());
         vec[0]
     }
 }
@@ -0,0 +1,312 @@
+use anyhow::{anyhow, Context};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::consensus::finality::Checkpoint;
+use crate::storage::Storage;
+use crate::transaction::utxo::UtxoTransaction;
+use crate::{Block, Transaction};
+
+/// Append-only file holding one JSON-encoded block per line.
+const BLOCKS_FILE: &str = "blocks.jsonl";
+/// Hash of the last block of the active chain.
+const TIP_FILE: &str = "tip";
+/// JSON-encoded last block finalized by the validators.
+const FINALIZED_FILE: &str = "finalized.json";
+/// JSON-encoded transactions waiting in the pool.
+const POOL_FILE: &str = "pool.json";
+/// JSON-encoded UTXO transactions waiting in the pool.
+const UTXO_POOL_FILE: &str = "utxo_pool.json";
+
+/// Block storage in a data directory.
+/// Blocks are only appended, so a crash can damage at most the last line.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    file: File,
+    len: u64,
+    /// Hash of every stored block, including those on side branches.
+    hashes: HashSet<String>,
+}
+
+impl FileStore {
+    /// Open the data directory, discarding a block left half-written by a crash.
+    pub fn open(dir: &Path) -> anyhow::Result<FileStore> {
+        println!("Opening data directory: {}", dir.display());
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create data directory {}", dir.display()))?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(BLOCKS_FILE))?;
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut store = FileStore {
+            dir: dir.to_path_buf(),
+            file,
+            len: 0,
+            hashes: HashSet::new(),
+        };
+        let mut count = 0;
+        let mut lines = content.split_inclusive(|x| *x == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            let is_last = lines.peek().is_none();
+            // 改行で終わっていない最後の行は、書き込み途中でクラッシュした残骸とみなす
+            let block = match line.strip_suffix(b"\n") {
+                Some(line) => serde_json::from_slice::<Block>(line).ok(),
+                None => None,
+            };
+            match block {
+                Some(block) => {
+                    store.check_parent(&block)?;
+                    store.hashes.insert(block.header.hash());
+                    store.len += line.len() as u64;
+                    count += 1;
+                }
+                None if is_last => {
+                    eprintln!(
+                        "Discarding a truncated block at the end of {} ({} bytes)",
+                        BLOCKS_FILE,
+                        line.len()
+                    );
+                    store.file.set_len(store.len)?;
+                    store.file.sync_all()?;
+                }
+                None => {
+                    return Err(anyhow!(
+                        "{} is corrupted at offset {}",
+                        BLOCKS_FILE,
+                        store.len
+                    ))
+                }
+            };
+        }
+        println!("{} blocks were found in {}", count, BLOCKS_FILE);
+        Ok(store)
+    }
+
+    /// Check that the parent of a block is already stored,
+    /// so that the blocks can be read back in an order in which they connect.
+    fn check_parent(&self, block: &Block) -> anyhow::Result<()> {
+        match &block.header.previous_block_hash {
+            Some(parent) if !self.hashes.contains(parent) => {
+                Err(anyhow!("parent block {} is not stored", parent))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace a file so that a crash leaves either the old or the new content.
+    fn write_atomically(&self, name: &str, content: &[u8]) -> anyhow::Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(name))?;
+        Ok(())
+    }
+
+    fn read_if_exists(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Storage for FileStore {
+    /// Write a block and flush it to the disk.
+    fn put_block(&mut self, block: &Block) -> anyhow::Result<()> {
+        if self.hashes.contains(&block.header.hash()) {
+            return Ok(());
+        }
+        self.check_parent(block)?;
+        let mut line = serde_json::to_vec(block)?;
+        line.push(b'\n');
+        // 書き込みに失敗したら途中までの行を切り捨て、次の追記が壊れた行の後ろに続かないようにする
+        if let Err(e) = self
+            .file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data())
+        {
+            self.file.set_len(self.len)?;
+            return Err(e.into());
+        }
+        self.hashes.insert(block.header.hash());
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn get_blocks(&mut self) -> anyhow::Result<Vec<Block>> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut blocks = Vec::new();
+        for line in BufReader::new(&self.file).lines() {
+            blocks.push(serde_json::from_str(&line?)?);
+        }
+        Ok(blocks)
+    }
+
+    fn put_tip(&mut self, hash: &str) -> anyhow::Result<()> {
+        self.write_atomically(TIP_FILE, hash.as_bytes())
+    }
+
+    fn get_tip(&mut self) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .read_if_exists(TIP_FILE)?
+            .map(|x| String::from_utf8_lossy(&x).to_string()))
+    }
+
+    fn put_finalized(&mut self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        self.write_atomically(FINALIZED_FILE, &serde_json::to_vec(checkpoint)?)
+    }
+
+    fn get_finalized(&mut self) -> anyhow::Result<Option<Checkpoint>> {
+        match self.read_if_exists(FINALIZED_FILE)? {
+            Some(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_pool_entries(&mut self, transactions: &[Transaction]) -> anyhow::Result<()> {
+        self.write_atomically(POOL_FILE, &serde_json::to_vec(transactions)?)
+    }
+
+    fn get_pool_entries(&mut self) -> anyhow::Result<Vec<Transaction>> {
+        match self.read_if_exists(POOL_FILE)? {
+            Some(content) => Ok(serde_json::from_slice(&content)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn put_utxo_pool_entries(&mut self, transactions: &[UtxoTransaction]) -> anyhow::Result<()> {
+        self.write_atomically(UTXO_POOL_FILE, &serde_json::to_vec(transactions)?)
+    }
+
+    fn get_utxo_pool_entries(&mut self) -> anyhow::Result<Vec<UtxoTransaction>> {
+        match self.read_if_exists(UTXO_POOL_FILE)? {
+            Some(content) => Ok(serde_json::from_slice(&content)?),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// Empty data directory only used by the test of the given name.
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mincoin-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn block(parent: &Block, miner: &str) -> Block {
+        let coinbase = Transaction::new_coinbase(miner, 50).to_string();
+        Block::new(
+            vec![coinbase],
+            Some(parent.header.hash()),
+            1,
+            parent.header.timestamp + 10,
+        )
+    }
+
+    fn genesis() -> Block {
+        let allocation = Transaction::new_coinbase("alice", 1000).to_string();
+        Block::new(vec![allocation], None, 1, 0)
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<String> {
+        blocks.iter().map(|x| x.header.hash()).collect()
+    }
+
+    fn append(dir: &Path, content: &[u8]) {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(BLOCKS_FILE))
+            .unwrap();
+        file.write_all(content).unwrap();
+    }
+
+    #[test]
+    fn truncated_last_line_is_discarded_and_the_next_block_is_appended() {
+        let dir = data_dir("truncated-last-line");
+        let genesis = genesis();
+        let first = block(&genesis, "a");
+        let second = block(&first, "b");
+        let mut store = FileStore::open(&dir).unwrap();
+        store.put_block(&genesis).unwrap();
+        store.put_block(&first).unwrap();
+        drop(store);
+        // 書き込み途中でクラッシュしたように、次のブロックの前半だけを残す
+        let line = serde_json::to_vec(&second).unwrap();
+        append(&dir, &line[..line.len() / 2]);
+
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(
+            hashes(&store.get_blocks().unwrap()),
+            hashes(&[genesis.clone(), first.clone()])
+        );
+        store.put_block(&second).unwrap();
+        drop(store);
+
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(
+            hashes(&store.get_blocks().unwrap()),
+            hashes(&[genesis, first, second])
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn broken_line_before_the_last_one_is_reported() {
+        let dir = data_dir("broken-line");
+        let genesis = genesis();
+        let mut store = FileStore::open(&dir).unwrap();
+        store.put_block(&genesis).unwrap();
+        drop(store);
+        append(&dir, b"{\"header\":\n");
+        let mut line = serde_json::to_vec(&block(&genesis, "a")).unwrap();
+        line.push(b'\n');
+        append(&dir, &line);
+
+        let e = FileStore::open(&dir).unwrap_err();
+        assert!(e.to_string().contains("corrupted"), "{}", e);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stored_blocks_are_known_by_hash_after_reopening() {
+        let dir = data_dir("known-by-hash");
+        let genesis = genesis();
+        let first = block(&genesis, "a");
+        let side = block(&genesis, "b");
+        let mut store = FileStore::open(&dir).unwrap();
+        for block in [&genesis, &first, &side] {
+            store.put_block(block).unwrap();
+        }
+        drop(store);
+
+        let mut store = FileStore::open(&dir).unwrap();
+        let len = store.len;
+        // 既に保存されているブロックは書き足さない
+        store.put_block(&first).unwrap();
+        assert_eq!(store.len, len);
+        assert_eq!(
+            hashes(&store.get_blocks().unwrap()),
+            hashes(&[genesis, first.clone(), side])
+        );
+        // 親が保存されていないブロックは、読み直したときに繋がらないので拒む
+        let orphan = block(&block(&first, "c"), "d");
+        assert!(store.put_block(&orphan).is_err());
+        assert_eq!(store.len, len);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
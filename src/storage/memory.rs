@@ -0,0 +1,72 @@
+use std::collections::HashSet;
+
+use crate::consensus::finality::Checkpoint;
+use crate::storage::Storage;
+use crate::transaction::utxo::UtxoTransaction;
+use crate::{Block, Transaction};
+
+/// Storage which keeps everything in memory and loses it on exit.
+#[derive(Default)]
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+    hashes: HashSet<String>,
+    tip: Option<String>,
+    finalized: Option<Checkpoint>,
+    pool: Vec<Transaction>,
+    utxo_pool: Vec<UtxoTransaction>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl Storage for MemoryStore {
+    fn put_block(&mut self, block: &Block) -> anyhow::Result<()> {
+        if self.hashes.insert(block.header.hash()) {
+            self.blocks.push(block.clone());
+        };
+        Ok(())
+    }
+
+    fn get_blocks(&mut self) -> anyhow::Result<Vec<Block>> {
+        Ok(self.blocks.clone())
+    }
+
+    fn put_tip(&mut self, hash: &str) -> anyhow::Result<()> {
+        self.tip = Some(hash.to_string());
+        Ok(())
+    }
+
+    fn get_tip(&mut self) -> anyhow::Result<Option<String>> {
+        Ok(self.tip.clone())
+    }
+
+    fn put_finalized(&mut self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        self.finalized = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    fn get_finalized(&mut self) -> anyhow::Result<Option<Checkpoint>> {
+        Ok(self.finalized.clone())
+    }
+
+    fn put_pool_entries(&mut self, transactions: &[Transaction]) -> anyhow::Result<()> {
+        self.pool = transactions.to_vec();
+        Ok(())
+    }
+
+    fn get_pool_entries(&mut self) -> anyhow::Result<Vec<Transaction>> {
+        Ok(self.pool.clone())
+    }
+
+    fn put_utxo_pool_entries(&mut self, transactions: &[UtxoTransaction]) -> anyhow::Result<()> {
+        self.utxo_pool = transactions.to_vec();
+        Ok(())
+    }
+
+    fn get_utxo_pool_entries(&mut self) -> anyhow::Result<Vec<UtxoTransaction>> {
+        Ok(self.utxo_pool.clone())
+    }
+}
@@ -0,0 +1,75 @@
+pub mod file;
+pub mod memory;
+
+use crate::consensus::finality::Checkpoint;
+use crate::transaction::utxo::UtxoTransaction;
+use crate::{Block, Transaction};
+
+/// Backend keeping the state of a node: blocks, the chain tip and the transaction pool.
+pub trait Storage: Send {
+    /// Store a block. Storing an already known block does nothing.
+    fn put_block(&mut self, block: &Block) -> anyhow::Result<()>;
+    /// Return every stored block in the order they were stored.
+    fn get_blocks(&mut self) -> anyhow::Result<Vec<Block>>;
+
+    /// Store the hash of the last block of the active chain.
+    fn put_tip(&mut self, hash: &str) -> anyhow::Result<()>;
+    fn get_tip(&mut self) -> anyhow::Result<Option<String>>;
+
+    /// Store the last block finalized by the validators.
+    fn put_finalized(&mut self, checkpoint: &Checkpoint) -> anyhow::Result<()>;
+    fn get_finalized(&mut self) -> anyhow::Result<Option<Checkpoint>>;
+
+    /// Replace the stored transaction pool.
+    fn put_pool_entries(&mut self, transactions: &[Transaction]) -> anyhow::Result<()>;
+    fn get_pool_entries(&mut self) -> anyhow::Result<Vec<Transaction>>;
+
+    /// Replace the stored pool of UTXO transactions.
+    fn put_utxo_pool_entries(&mut self, transactions: &[UtxoTransaction]) -> anyhow::Result<()>;
+    fn get_utxo_pool_entries(&mut self) -> anyhow::Result<Vec<UtxoTransaction>>;
+}
+
+/// Storage of a node, selected at runtime by the command line options.
+pub type NodeStorage = Box<dyn Storage>;
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn put_block(&mut self, block: &Block) -> anyhow::Result<()> {
+        (**self).put_block(block)
+    }
+
+    fn get_blocks(&mut self) -> anyhow::Result<Vec<Block>> {
+        (**self).get_blocks()
+    }
+
+    fn put_tip(&mut self, hash: &str) -> anyhow::Result<()> {
+        (**self).put_tip(hash)
+    }
+
+    fn get_tip(&mut self) -> anyhow::Result<Option<String>> {
+        (**self).get_tip()
+    }
+
+    fn put_finalized(&mut self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        (**self).put_finalized(checkpoint)
+    }
+
+    fn get_finalized(&mut self) -> anyhow::Result<Option<Checkpoint>> {
+        (**self).get_finalized()
+    }
+
+    fn put_pool_entries(&mut self, transactions: &[Transaction]) -> anyhow::Result<()> {
+        (**self).put_pool_entries(transactions)
+    }
+
+    fn get_pool_entries(&mut self) -> anyhow::Result<Vec<Transaction>> {
+        (**self).get_pool_entries()
+    }
+
+    fn put_utxo_pool_entries(&mut self, transactions: &[UtxoTransaction]) -> anyhow::Result<()> {
+        (**self).put_utxo_pool_entries(transactions)
+    }
+
+    fn get_utxo_pool_entries(&mut self) -> anyhow::Result<Vec<UtxoTransaction>> {
+        (**self).get_utxo_pool_entries()
+    }
+}
@@ -1,25 +1,174 @@
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::crypt::keys::{self, KeyPair};
+use crate::storage::Storage;
+use crate::transaction::utxo::UtxoTransaction;
+
+/// Sender of a coinbase transaction, which issues new coins to the miner of a block.
+pub const COINBASE_SENDER: &str = "coinbase";
+
+/// Lowest fee a transaction must pay to be pooled and relayed to peers.
+pub const MIN_RELAY_FEE: i32 = 1;
+
+/// How far ahead of the next nonce of its sender a pooled transaction may be.
+pub const MAX_NONCE_GAP: u64 = 64;
+
+/// Most transactions a sender may have waiting in the pool.
+pub const MAX_QUEUED_PER_SENDER: usize = 16;
+
+/// Fee paid per 1000 bytes of the encoded transaction, by which block assembly
+/// prefers one transaction to another.
+pub fn fee_rate(fee: i32, encoded: &str) -> i64 {
+    i64::from(fee) * 1000 / encoded.len().max(1) as i64
+}
+
+/// Change of the signer set proposed by a signer. (Proof of Authority)
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SignerVote {
+    Add,
+    Remove,
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Transaction {
     sender: String,
     recipient: String,
     value: i32,
+    /// Paid by `sender` on top of `value` to the miner of the block including it.
+    #[serde(default)]
+    fee: i32,
+    /// Sequence number of the transactions sent from `sender`, starting from 0,
+    /// so that a signed transaction cannot be replayed.
+    #[serde(default)]
+    nonce: u64,
+    /// Set only on governance transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vote: Option<SignerVote>,
+    /// Key of the sender, whose address must be `sender`. Not set on a coinbase or a vote.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    public_key: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    signature: String,
 }
 
 impl Transaction {
-    pub fn new(sender: impl Into<String>, recipient: impl Into<String>, value: i32) -> Transaction {
+    fn new(sender: impl Into<String>, recipient: impl Into<String>, value: i32) -> Transaction {
         Transaction {
             sender: sender.into(),
             recipient: recipient.into(),
             value,
+            fee: 0,
+            nonce: 0,
+            vote: None,
+            public_key: String::new(),
+            signature: String::new(),
         }
     }
+
+    /// Create the `nonce`-th payment from the address of `key`, signed by it.
+    pub fn new_signed(
+        key: &KeyPair,
+        recipient: impl Into<String>,
+        value: i32,
+        fee: i32,
+        nonce: u64,
+    ) -> Transaction {
+        let mut txn = Transaction {
+            fee,
+            nonce,
+            public_key: key.public_key(),
+            ..Transaction::new(key.address(), recipient, value)
+        };
+        txn.signature = key.sign(&txn.to_unsigned_string());
+        txn
+    }
+
+    /// Create a governance transaction in which the signer `voter` votes
+    /// for adding `candidate` to or removing it from the signer set.
+    pub fn new_vote(
+        voter: impl Into<String>,
+        candidate: impl Into<String>,
+        vote: SignerVote,
+    ) -> Transaction {
+        Transaction {
+            vote: Some(vote),
+            ..Transaction::new(voter, candidate, 0)
+        }
+    }
+
+    /// Create the first transaction of a block, paying `value` to the miner.
+    pub fn new_coinbase(recipient: impl Into<String>, value: i32) -> Transaction {
+        Transaction::new(COINBASE_SENDER, recipient, value)
+    }
+
+    /// Canonical encoding the signature is made over, i.e. the JSON without the signature.
+    fn to_unsigned_string(&self) -> String {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        unsigned.to_string()
+    }
+
+    /// Check that the sender is the address of the public key which signed this transaction.
+    pub fn has_valid_signature(&self) -> bool {
+        keys::is_public_key(&self.public_key)
+            && keys::address(&self.public_key) == self.sender
+            && keys::verify(
+                &self.public_key,
+                &self.to_unsigned_string(),
+                &self.signature,
+            )
+    }
+
+    /// Check the fields of a transaction received from others.
+    /// A coinbase transaction is not well-formed outside of the head of a block,
+    /// and a governance transaction is left to the consensus engine to check.
+    /// The signature is checked separately by `has_valid_signature`.
+    pub fn is_well_formed(&self) -> bool {
+        !self.sender.is_empty()
+            && !self.recipient.is_empty()
+            && self.value > 0
+            && self.fee >= 0
+            && !self.is_coinbase()
+            && self.vote.is_none()
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == COINBASE_SENDER
+    }
+
+    pub fn get_vote(&self) -> Option<SignerVote> {
+        self.vote
+    }
+
+    pub fn get_sender(&self) -> &str {
+        &self.sender
+    }
+
+    pub fn get_recipient(&self) -> &str {
+        &self.recipient
+    }
+
+    pub fn get_value(&self) -> i32 {
+        self.value
+    }
+
+    pub fn get_fee(&self) -> i32 {
+        self.fee
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
 }
 
-impl ToString for Transaction {
-    fn to_string(&self) -> String {
-        serde_json::to_string(self).unwrap()
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
     }
 }
 
@@ -36,38 +185,322 @@ where
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct TransactionPool {
-    transactions: Vec<Transaction>,
+pub struct TransactionPool<S: Storage> {
+    /// Transactions of each sender, ordered by their nonces.
+    queues: BTreeMap<String, BTreeMap<u64, Transaction>>,
+    /// Transactions waiting on a UTXO ledger.
+    utxo_transactions: Vec<UtxoTransaction>,
+    storage: Arc<Mutex<S>>,
 }
 
-impl TransactionPool {
-    pub fn new() -> TransactionPool {
+impl<S: Storage> TransactionPool<S> {
+    /// Restore the transactions kept in the storage.
+    pub fn new(storage: Arc<Mutex<S>>) -> TransactionPool<S> {
         println!("Initializing TransactionPool ...");
-        TransactionPool {
-            transactions: Vec::new(),
+        let transactions = storage
+            .lock()
+            .unwrap()
+            .get_pool_entries()
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load the transaction pool: {}", e);
+                Vec::new()
+            });
+        let utxo_transactions = storage
+            .lock()
+            .unwrap()
+            .get_utxo_pool_entries()
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load the UTXO transaction pool: {}", e);
+                Vec::new()
+            });
+        let mut pool = TransactionPool {
+            queues: BTreeMap::new(),
+            utxo_transactions,
+            storage,
+        };
+        for txn in transactions {
+            pool.enqueue(txn);
+        }
+        pool
+    }
+
+    /// Write the current transactions through to the storage.
+    fn save(&self) {
+        let transactions: Vec<Transaction> = self
+            .queues
+            .values()
+            .flat_map(|x| x.values())
+            .cloned()
+            .collect();
+        let mut storage = self.storage.lock().unwrap();
+        if let Err(e) = storage.put_pool_entries(&transactions) {
+            eprintln!("Failed to store the transaction pool: {}", e);
+        };
+        if let Err(e) = storage.put_utxo_pool_entries(&self.utxo_transactions) {
+            eprintln!("Failed to store the UTXO transaction pool: {}", e);
+        };
+    }
+
+    /// Put a transaction in the queue of its sender, unless the sender already has
+    /// a pooled transaction with the same nonce.
+    fn enqueue(&mut self, transaction: Transaction) -> bool {
+        let queue = self
+            .queues
+            .entry(transaction.get_sender().to_string())
+            .or_default();
+        if queue.contains_key(&transaction.get_nonce()) {
+            return false;
         }
+        queue.insert(transaction.get_nonce(), transaction);
+        true
     }
 
-    pub fn set_new_transaction(&mut self, transaction: Transaction) {
+    /// Check that a transaction received from others may wait in the pool,
+    /// given the next nonce and the balance of its sender at the tip of the active chain.
+    /// Its sender must be able to pay it on top of the transactions already pooled,
+    /// and cannot queue too many of them or queue them too far ahead.
+    pub fn check_admission(
+        &self,
+        transaction: &Transaction,
+        next_nonce: u64,
+        balance: i64,
+    ) -> anyhow::Result<()> {
+        let nonce = transaction.get_nonce();
+        if nonce < next_nonce {
+            bail!(
+                "nonce {} of its sender was already used, expected {} or later",
+                nonce,
+                next_nonce
+            );
+        }
+        if nonce - next_nonce > MAX_NONCE_GAP {
+            bail!(
+                "nonce {} is more than {} ahead of the next nonce {} of its sender",
+                nonce,
+                MAX_NONCE_GAP,
+                next_nonce
+            );
+        }
+        // 既にブロックに入ったnonceのものは、まだ取り除かれていなくても数えない
+        let queued: Vec<&Transaction> = self
+            .queues
+            .get(transaction.get_sender())
+            .map(|x| x.range(next_nonce..).map(|(_, txn)| txn).collect())
+            .unwrap_or_default();
+        if queued.len() >= MAX_QUEUED_PER_SENDER {
+            bail!(
+                "its sender already has {} transactions pooled",
+                queued.len()
+            );
+        }
+        let spent: i64 = queued
+            .iter()
+            .chain([&transaction])
+            .map(|x| i64::from(x.get_value()) + i64::from(x.get_fee()))
+            .sum();
+        if spent > balance {
+            bail!(
+                "its sender owns {} but would spend {} with the pooled transactions",
+                balance,
+                spent
+            );
+        }
+        Ok(())
+    }
+
+    /// Add a transaction. Return false if its sender already has a pooled transaction
+    /// with the same nonce.
+    pub fn set_new_transaction(&mut self, transaction: Transaction) -> bool {
         println!("set_new_transaction is called: {:#?}", transaction);
-        self.transactions.push(transaction);
+        if !self.enqueue(transaction) {
+            return false;
+        }
+        self.save();
+        true
     }
 
-    pub fn clear_my_transactions(&mut self, index: usize) {
-        if index <= self.transactions.len() {
-            let new_txns = self.transactions[index..].to_vec();
-            println!("transaction is now refreshed ... ({:#?})", new_txns);
-            self.transactions = new_txns;
-        };
+    /// Add a UTXO transaction unless a pooled transaction already spends one of its inputs.
+    pub fn set_new_utxo_transaction(&mut self, transaction: UtxoTransaction) -> bool {
+        if self.spends_pooled_input(&transaction) {
+            return false;
+        }
+        println!("set_new_utxo_transaction is called: {:#?}", transaction);
+        self.utxo_transactions.push(transaction);
+        self.save();
+        true
+    }
+
+    fn spends_pooled_input(&self, transaction: &UtxoTransaction) -> bool {
+        self.utxo_transactions
+            .iter()
+            .flat_map(|x| x.get_inputs())
+            .any(|x| transaction.get_inputs().contains(x))
+    }
+
+    /// Remove transactions that were included in a block.
+    pub fn remove_included(&mut self, transactions: &[String]) {
+        for txn in transactions {
+            let txn = match serde_json::from_str::<Transaction>(txn) {
+                Ok(txn) if txn.is_well_formed() => txn,
+                _ => continue,
+            };
+            // 同じnonce以下のトランザクションは、もうブロックに取り込めない
+            if let Some(queue) = self.queues.get_mut(txn.get_sender()) {
+                queue.retain(|nonce, _| *nonce > txn.get_nonce());
+            };
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+        // 同じ出力を使う別のトランザクションは、二重支払いになるのでもう取り込めない
+        let spent: Vec<_> = transactions
+            .iter()
+            .filter_map(|x| serde_json::from_str::<UtxoTransaction>(x).ok())
+            .flat_map(|x| x.get_inputs().to_vec())
+            .collect();
+        self.utxo_transactions
+            .retain(|x| !x.get_inputs().iter().any(|input| spent.contains(input)));
+        println!("transaction is now refreshed ... ({:#?})", self.queues);
+        self.save();
+    }
+
+    /// Put back transactions of a block which left the active chain.
+    pub fn restore_transactions(&mut self, transactions: &[String]) {
+        for txn in transactions {
+            if let Ok(txn) = serde_json::from_str::<Transaction>(txn) {
+                // coinbaseや投票はそのブロックだけのものなので、プールには戻さない
+                if txn.is_well_formed() {
+                    self.enqueue(txn);
+                };
+            } else if let Ok(txn) = serde_json::from_str::<UtxoTransaction>(txn) {
+                if !self.spends_pooled_input(&txn) {
+                    self.utxo_transactions.push(txn);
+                };
+            };
+        }
+        println!("transaction is now restored ... ({:#?})", self.queues);
+        self.save();
     }
 
-    pub fn get_stored_transactions(&self) -> Option<Vec<Transaction>> {
-        if !self.transactions.is_empty() {
-            Some(self.transactions.clone())
+    /// Pooled transactions which can go into the next block, in the form a block stores them,
+    /// highest fee rate first.
+    /// Transactions of a sender are taken in the order of their nonces starting from
+    /// `next_nonce` of the sender, and those after a gap wait until it is filled.
+    pub fn get_block_candidates(&self, next_nonce: impl Fn(&str) -> u64) -> Option<Vec<String>> {
+        let mut ready: Vec<VecDeque<(i64, String)>> = Vec::new();
+        for (sender, queue) in &self.queues {
+            let next = next_nonce(sender);
+            let queue = queue
+                .range(next..)
+                .zip(next..)
+                .take_while(|((nonce, _), expected)| *nonce == expected)
+                .map(|((_, txn), _)| {
+                    let encoded = txn.to_string();
+                    (fee_rate(txn.get_fee(), &encoded), encoded)
+                });
+            ready.push(queue.collect());
+        }
+        for txn in &self.utxo_transactions {
+            let encoded = txn.to_string();
+            ready.push(VecDeque::from([(
+                fee_rate(txn.get_fee(), &encoded),
+                encoded,
+            )]));
+        }
+        // nonceの順を崩さないよう、各キューの先頭同士で手数料率を比べる
+        let mut candidates = Vec::new();
+        while let Some(queue) = ready
+            .iter_mut()
+            .filter(|x| !x.is_empty())
+            .max_by_key(|x| x[0].0)
+        {
+            candidates.extend(queue.pop_front().map(|(_, txn)| txn));
+        }
+        if !candidates.is_empty() {
+            Some(candidates)
         } else {
             println!("Currently, it seems transaction pool is empty ...");
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStore;
+
+    fn new_pool() -> TransactionPool<MemoryStore> {
+        TransactionPool::new(Arc::new(Mutex::new(MemoryStore::new())))
+    }
+
+    fn payment(nonce: u64) -> Transaction {
+        Transaction::new_signed(&KeyPair::from_seed("alice"), "bob", 10, 1, nonce)
+    }
+
+    #[test]
+    fn nonce_too_far_ahead_is_rejected() {
+        let pool = new_pool();
+        assert!(pool.check_admission(&payment(2), 2, 1000).is_ok());
+        assert!(pool
+            .check_admission(&payment(2 + MAX_NONCE_GAP), 2, 1000)
+            .is_ok());
+        assert!(pool
+            .check_admission(&payment(3 + MAX_NONCE_GAP), 2, 1000)
+            .is_err());
+        assert!(pool.check_admission(&payment(1), 2, 1000).is_err());
+    }
+
+    #[test]
+    fn queue_of_a_sender_is_capped() {
+        let mut pool = new_pool();
+        for nonce in 0..MAX_QUEUED_PER_SENDER as u64 {
+            pool.check_admission(&payment(nonce), 0, 1000).unwrap();
+            assert!(pool.set_new_transaction(payment(nonce)));
+        }
+        let next = payment(MAX_QUEUED_PER_SENDER as u64);
+        assert!(pool.check_admission(&next, 0, 1000).is_err());
+        // ブロックに入った分はもう数えない
+        assert!(pool.check_admission(&next, 1, 1000).is_ok());
+    }
+
+    #[test]
+    fn balance_must_cover_pooled_payments_and_fees() {
+        let mut pool = new_pool();
+        assert!(pool.check_admission(&payment(0), 0, 10).is_err());
+        assert!(pool.check_admission(&payment(0), 0, 11).is_ok());
+        assert!(pool.set_new_transaction(payment(0)));
+        assert!(pool.check_admission(&payment(1), 0, 21).is_err());
+        assert!(pool.check_admission(&payment(1), 0, 22).is_ok());
+    }
+
+    #[test]
+    fn candidates_follow_the_fee_rate_without_breaking_the_nonce_order() {
+        let mut pool = new_pool();
+        let txn = |seed: &str, fee, nonce| {
+            Transaction::new_signed(&KeyPair::from_seed(seed), "bob", 10, fee, nonce)
+        };
+        let transactions = [
+            txn("alice", 1, 0),
+            txn("alice", 50, 1),
+            txn("carol", 10, 0),
+            txn("dave", 5, 0),
+            // nonce 0が来るまで取り込めない
+            txn("erin", 100, 1),
+        ];
+        for transaction in &transactions {
+            assert!(pool.set_new_transaction(transaction.clone()));
+        }
+
+        // aliceのnonce 1は手数料が一番高くても、nonce 0より先には出ない
+        let expected: Vec<String> = [2, 3, 0, 1]
+            .iter()
+            .map(|x| transactions[*x].to_string())
+            .collect();
+        assert_eq!(pool.get_block_candidates(|_| 0), Some(expected));
+        // 先頭のnonceがブロックに入っていれば、その次から並ぶ
+        let next_nonce = |x: &str| u64::from(x == transactions[0].get_sender());
+        assert_eq!(
+            pool.get_block_candidates(next_nonce).unwrap()[0],
+            transactions[1].to_string()
+        );
+    }
+}
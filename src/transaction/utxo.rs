@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::crypt::keys::{self, KeyPair};
+use crate::crypt::sha;
+
+/// Reference to an output of an earlier transaction.
+/// Coins issued by the coinbase transaction at position `index` of a block are
+/// referenced by the hash of that block, since coinbase transactions may repeat.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+pub struct OutPoint {
+    pub txid: String,
+    pub index: u32,
+}
+
+/// Coins owned by an address until an input spends them.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct TxOutput {
+    pub owner: String,
+    pub value: i32,
+}
+
+/// Transaction of the UTXO ledger, which spends earlier outputs and creates new ones.
+/// Every spent output must be owned by the address of the signing key.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UtxoTransaction {
+    inputs: Vec<OutPoint>,
+    outputs: Vec<TxOutput>,
+    /// Paid to the miner of the block including it. The spent outputs must add up
+    /// to exactly the created outputs and the fee.
+    #[serde(default)]
+    fee: i32,
+    public_key: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    signature: String,
+}
+
+impl UtxoTransaction {
+    /// Create a transaction spending outputs owned by the address of `key`, signed by it.
+    pub fn new_signed(
+        key: &KeyPair,
+        inputs: Vec<OutPoint>,
+        outputs: Vec<TxOutput>,
+        fee: i32,
+    ) -> UtxoTransaction {
+        let mut txn = UtxoTransaction {
+            inputs,
+            outputs,
+            fee,
+            public_key: key.public_key(),
+            signature: String::new(),
+        };
+        txn.signature = key.sign(&txn.to_string());
+        txn
+    }
+
+    /// Address which must own every spent output.
+    pub fn get_signer_address(&self) -> String {
+        keys::address(&self.public_key)
+    }
+
+    /// Check the signature over the JSON without the signature.
+    pub fn has_valid_signature(&self) -> bool {
+        let unsigned = UtxoTransaction {
+            signature: String::new(),
+            ..self.clone()
+        };
+        keys::verify(&self.public_key, &unsigned.to_string(), &self.signature)
+    }
+
+    /// Identifier the outputs of this transaction are referenced by.
+    pub fn txid(&self) -> String {
+        sha::get_double_sha256(self.to_string())
+    }
+
+    /// Check the fields of a transaction received from others.
+    /// Whether the inputs can be spent is left to the UTXO set.
+    pub fn is_well_formed(&self) -> bool {
+        let unique_inputs: HashSet<&OutPoint> = self.inputs.iter().collect();
+        !self.inputs.is_empty()
+            && unique_inputs.len() == self.inputs.len()
+            && !self.outputs.is_empty()
+            && self.fee >= 0
+            && self
+                .outputs
+                .iter()
+                .all(|x| !x.owner.is_empty() && x.value > 0)
+    }
+
+    pub fn get_inputs(&self) -> &[OutPoint] {
+        &self.inputs
+    }
+
+    pub fn get_outputs(&self) -> &[TxOutput] {
+        &self.outputs
+    }
+
+    pub fn get_fee(&self) -> i32 {
+        self.fee
+    }
+
+    /// Sum of the created outputs and the fee, which the spent outputs must add up to.
+    pub fn output_value(&self) -> i64 {
+        self.outputs.iter().map(|x| i64::from(x.value)).sum::<i64>() + i64::from(self.fee)
+    }
+}
+
+impl std::fmt::Display for UtxoTransaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}